@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+
+use crate::{
+    instructions::{Call, Halt, In, Instruction, Out, Pop, Ret, Rmem, Wmem},
+    lift::footprint,
+    VM,
+};
+
+/// Memoizes calls to one routine as the program runs. A call is keyed on the
+/// registers the routine can read, and its result is only reused while the
+/// memory it read still holds the same values. Memoizing stops for good as
+/// soon as a call turns out not to be pure: writing memory, doing I/O or
+/// reaching into its caller's stack.
+pub(crate) struct CallCache {
+    entry: u16,
+    inputs: Vec<usize>,
+    outputs: Vec<usize>,
+    results: HashMap<Vec<u16>, Cached>,
+    /// Calls in progress, outermost first
+    calls: Vec<Pending>,
+    /// Why memoizing stopped
+    disabled: Option<String>,
+    hits: u64,
+    misses: u64,
+}
+
+/// What a call left in the output registers, and the memory it read
+struct Cached {
+    reads: Vec<(u16, u16)>,
+    outputs: Vec<u16>,
+}
+
+struct Pending {
+    key: Vec<u16>,
+    /// The stack's depth with the return address pushed
+    depth: usize,
+    /// Memory read so far, passed on to the caller on return
+    reads: Vec<(u16, u16)>,
+}
+
+impl CallCache {
+    pub(crate) fn new(memory: &[u16], entry: u16) -> Result<Self, String> {
+        let (inputs, outputs) = footprint(memory, entry)?;
+        Ok(Self {
+            entry,
+            inputs,
+            outputs,
+            results: HashMap::new(),
+            calls: Vec::new(),
+            disabled: None,
+            hits: 0,
+            misses: 0,
+        })
+    }
+
+    /// The routine's address
+    pub(crate) fn entry(&self) -> u16 {
+        self.entry
+    }
+
+    /// Looks at the instruction about to run. Returns true if it was a call
+    /// answered from the cache, in which case the caller is already resumed.
+    pub(crate) fn intercept(
+        &mut self,
+        vm: &mut VM,
+        instruction: &dyn Instruction,
+        size: u16,
+    ) -> bool {
+        if self.disabled.is_some() {
+            return false;
+        }
+        let opcode = instruction.opcode();
+        if let Some(call) = self.calls.last() {
+            let violation = match opcode {
+                Wmem::OPCODE => Some("writes memory"),
+                In::OPCODE | Out::OPCODE => Some("does I/O"),
+                Halt::OPCODE => Some("halts"),
+                Pop::OPCODE if vm.stack.len() == call.depth => Some("pops its caller's stack"),
+                _ => None,
+            };
+            if let Some(violation) = violation {
+                self.disable(format!("{}: {instruction} {violation}", vm.pc));
+                return false;
+            }
+        }
+        match opcode {
+            Rmem::OPCODE if !self.calls.is_empty() => {
+                let addr = instruction.operands()[1].value(vm);
+                let read = (addr, vm.memory[addr as usize]);
+                self.calls.last_mut().unwrap().reads.push(read);
+            }
+            Ret::OPCODE if self.calls.last().map(|call| call.depth) == Some(vm.stack.len()) => {
+                let call = self.calls.pop().unwrap();
+                // The caller read whatever its callee did
+                if let Some(caller) = self.calls.last_mut() {
+                    caller.reads.extend(&call.reads);
+                }
+                let outputs = self.outputs.iter().map(|&reg| vm.registers[reg]);
+                let result = Cached {
+                    reads: call.reads,
+                    outputs: outputs.collect(),
+                };
+                self.results.insert(call.key, result);
+            }
+            Call::OPCODE if instruction.operands()[0].value(vm) == self.entry => {
+                let key = self.inputs.iter().map(|&reg| vm.registers[reg]).collect();
+                if let Some(result) = self.results.get(&key) {
+                    let memory = &vm.memory;
+                    if (result.reads.iter()).all(|&(addr, value)| memory[addr as usize] == value) {
+                        for (&reg, &value) in self.outputs.iter().zip(&result.outputs) {
+                            vm.registers[reg] = value;
+                        }
+                        if let Some(call) = self.calls.last_mut() {
+                            call.reads.extend(&result.reads);
+                        }
+                        self.hits += 1;
+                        vm.pc += size;
+                        return true;
+                    }
+                }
+                self.misses += 1;
+                self.calls.push(Pending {
+                    key,
+                    depth: vm.stack.len() + 1,
+                    reads: Vec::new(),
+                });
+            }
+            _ => {}
+        }
+        false
+    }
+
+    fn disable(&mut self, reason: String) {
+        eprintln!("Stopped memoizing calls to {}: {reason}", self.entry);
+        self.results.clear();
+        self.calls.clear();
+        self.disabled = Some(reason);
+    }
+
+    /// Writes a summary to stderr
+    pub(crate) fn report(&self) {
+        match &self.disabled {
+            Some(reason) => eprintln!("Calls to {} are not pure: {reason}", self.entry),
+            None => eprintln!(
+                "Memoized calls to {}: {} hits, {} misses",
+                self.entry, self.hits, self.misses
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{instructions::parse, side_effects::MockSideEffects, teleporter::Teleporter};
+
+    /// Runs from pc 0 until it reaches `until`, returning the instructions executed
+    fn run(vm: &mut VM, mut cache: Option<&mut CallCache>, until: u16) -> u64 {
+        let mut side_effects = MockSideEffects::default();
+        let mut steps = 0;
+        vm.pc = 0;
+        while vm.pc != until {
+            let (instruction, size) = parse(&vm.memory, vm.pc).unwrap();
+            steps += 1;
+            if let Some(cache) = cache.as_deref_mut() {
+                if cache.intercept(vm, instruction.as_ref(), size) {
+                    continue;
+                }
+            }
+            vm.pc += size;
+            instruction.execute(vm, &mut side_effects);
+        }
+        steps
+    }
+
+    #[test]
+    fn test_memoize_calls() {
+        // call 6027, the teleporter's confirmation, then stop at 2
+        let mut vm = VM::challenge();
+        vm.memory[..2].copy_from_slice(&[Call::OPCODE, 6027]);
+        (vm.registers[0], vm.registers[1], vm.registers[7]) = (3, 2, 3);
+        let expected = Teleporter::new(3).check(3, 2);
+        let slow = run(&mut vm.clone(), None, 2);
+        let mut cache = CallCache::new(&vm.memory, 6027).unwrap();
+        assert_eq!(
+            (cache.inputs.clone(), cache.outputs.clone()),
+            (vec![0, 1, 7], vec![0, 1])
+        );
+        let fast = run(&mut vm, Some(&mut cache), 2);
+        assert_eq!(vm.registers[0], expected);
+        assert!(fast * 10 < slow, "{fast} steps memoized, {slow} without");
+        assert!(cache.hits > 0 && cache.disabled.is_none());
+
+        // A routine at 10 that writes memory: wmem 100 reg0; ret
+        let mut vm = VM::challenge();
+        vm.memory[..2].copy_from_slice(&[Call::OPCODE, 10]);
+        vm.memory[10..14].copy_from_slice(&[Wmem::OPCODE, 100, 32768, Ret::OPCODE]);
+        let mut cache = CallCache::new(&vm.memory, 10).unwrap();
+        run(&mut vm, Some(&mut cache), 2);
+        let reason = cache.disabled.unwrap();
+        assert!(reason.starts_with("10: ") && reason.ends_with("writes memory"));
+    }
+}
@@ -0,0 +1,114 @@
+const CODE_LENGTH: usize = 12;
+
+/// A code for the challenge website, and where it was found
+pub(crate) struct Code {
+    pub(crate) code: String,
+    pub(crate) source: String,
+    /// The text as printed, if the code had to be flipped to read it
+    pub(crate) mirrored: Option<String>,
+}
+
+/// Picks codes out of text as it is printed. Codes are 12 letters of mixed
+/// case standing on their own, which no English word in the game looks like.
+/// A code seen in a mirror is printed back to front with `b`/`d` and `p`/`q`
+/// swapped, so it is flipped back.
+#[derive(Default)]
+pub(crate) struct CodeExtractor {
+    line: String,
+    pub(crate) codes: Vec<Code>,
+}
+
+impl CodeExtractor {
+    pub(crate) fn print(&mut self, c: char) {
+        if c == '\n' {
+            let line = std::mem::take(&mut self.line);
+            self.scan_line(&line, None);
+        } else {
+            self.line.push(c);
+        }
+    }
+
+    /// Scans text that doesn't come from the VM, like the architecture spec
+    pub(crate) fn scan(&mut self, text: &str, source: &str) {
+        for line in text.lines() {
+            self.scan_line(line, Some(source));
+        }
+    }
+
+    fn scan_line(&mut self, line: &str, source: Option<&str>) {
+        let words = line.split(|c: char| !c.is_ascii_alphabetic());
+        for word in words.filter(|word| is_code(word)) {
+            let (code, mirrored) = if line.contains("mirror") {
+                (flip(word), Some(word.to_string()))
+            } else {
+                (word.to_string(), None)
+            };
+            if self.codes.iter().any(|found| found.code == code) {
+                continue;
+            }
+            self.codes.push(Code {
+                code,
+                source: source.unwrap_or(line.trim()).into(),
+                mirrored,
+            });
+        }
+    }
+
+    /// Prints a numbered list of the codes found
+    pub(crate) fn finish(&mut self) {
+        let line = std::mem::take(&mut self.line);
+        self.scan_line(&line, None);
+        eprintln!("\nCodes found:");
+        for (n, code) in self.codes.iter().enumerate() {
+            match &code.mirrored {
+                Some(seen) => {
+                    eprintln!("{:>3}. {}  (seen as {seen} in a mirror)", n + 1, code.code)
+                }
+                None => eprintln!("{:>3}. {}  {}", n + 1, code.code, code.source),
+            }
+        }
+    }
+}
+
+fn is_code(word: &str) -> bool {
+    word.len() == CODE_LENGTH
+        && word.chars().any(|c| c.is_ascii_lowercase())
+        && word.chars().skip(1).any(|c| c.is_ascii_uppercase())
+}
+
+/// Reads text the right way round after seeing it in a mirror
+fn flip(text: &str) -> String {
+    text.chars()
+        .rev()
+        .map(|c| match c {
+            'b' => 'd',
+            'd' => 'b',
+            'p' => 'q',
+            'q' => 'p',
+            _ => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_extract_codes() {
+        let mut extractor = CodeExtractor::default();
+        let output = "this one into the challenge website: LygIPdNAemlv\n\
+            The cave acoustics dramatically change. Definitely no treasure.\n\
+            Through the mirror, you see \"VAUUvUAAiUTq\" scrawled in charcoal\n\
+            LygIPdNAemlv again\n    zJfAmIBPFOIy";
+        output.chars().for_each(|c| extractor.print(c));
+        extractor.finish();
+        let codes = extractor
+            .codes
+            .iter()
+            .map(|code| code.code.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(codes, vec!["LygIPdNAemlv", "pTUiAAUvUUAV", "zJfAmIBPFOIy"]);
+        assert_eq!(extractor.codes[1].mirrored.as_deref(), Some("VAUUvUAAiUTq"));
+    }
+}
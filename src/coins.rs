@@ -0,0 +1,274 @@
+use itertools::Itertools;
+
+use crate::{
+    explore::{MapRoom, WorldMap},
+    game::Game,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    /// An open slot for a coin
+    Blank,
+    Op(char),
+    Open,
+    Close,
+}
+
+/// The monument's equation, like `_ + _ * _^2 + _^3 - _ = 399`
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Equation {
+    tokens: Vec<Token>,
+    total: i64,
+}
+
+impl Equation {
+    /// Finds the equation in some text, e.g. the monument room's description
+    pub(crate) fn find(text: &str) -> Option<Self> {
+        text.lines().find_map(Self::parse)
+    }
+
+    pub(crate) fn parse(text: &str) -> Option<Self> {
+        let (left, right) = text.split_once('=')?;
+        let total = right.trim().parse().ok()?;
+        let mut tokens = Vec::new();
+        let mut chars = left.chars().peekable();
+        while let Some(c) = chars.next() {
+            let token = match c {
+                '_' => Token::Blank,
+                '+' | '-' | '*' | '/' | '^' => Token::Op(c),
+                '(' => Token::Open,
+                ')' => Token::Close,
+                '0'..='9' => {
+                    let mut number = c.to_digit(10)? as i64;
+                    while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+                        number = number * 10 + digit as i64;
+                        chars.next();
+                    }
+                    Token::Number(number)
+                }
+                _ if c.is_whitespace() => continue,
+                _ => return None,
+            };
+            tokens.push(token);
+        }
+        if !tokens.contains(&Token::Blank) {
+            return None;
+        }
+        Some(Self { tokens, total })
+    }
+
+    /// Number of open slots
+    pub(crate) fn blanks(&self) -> usize {
+        self.tokens
+            .iter()
+            .filter(|&&token| token == Token::Blank)
+            .count()
+    }
+
+    /// Whether the equation holds with these values in the slots, left to right
+    pub(crate) fn holds(&self, values: &[i64]) -> bool {
+        let mut values = values.iter();
+        let tokens = (self.tokens.iter())
+            .map(|&token| match token {
+                Token::Blank => Token::Number(*values.next().unwrap_or(&0)),
+                token => token,
+            })
+            .collect_vec();
+        let mut parser = Parser {
+            tokens: &tokens,
+            pos: 0,
+        };
+        parser.expr() == Some(self.total) && parser.pos == tokens.len()
+    }
+}
+
+/// Evaluates tokens with the usual precedence, `^` binding tightest
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn next_op(&mut self, ops: &str) -> Option<char> {
+        match self.tokens.get(self.pos) {
+            Some(&Token::Op(op)) if ops.contains(op) => {
+                self.pos += 1;
+                Some(op)
+            }
+            _ => None,
+        }
+    }
+
+    fn expr(&mut self) -> Option<i64> {
+        let mut value = self.term()?;
+        while let Some(op) = self.next_op("+-") {
+            let rhs = self.term()?;
+            value = if op == '+' {
+                value.checked_add(rhs)?
+            } else {
+                value.checked_sub(rhs)?
+            };
+        }
+        Some(value)
+    }
+
+    fn term(&mut self) -> Option<i64> {
+        let mut value = self.power()?;
+        while let Some(op) = self.next_op("*/") {
+            let rhs = self.power()?;
+            value = if op == '*' {
+                value.checked_mul(rhs)?
+            } else {
+                value.checked_div(rhs)?
+            };
+        }
+        Some(value)
+    }
+
+    fn power(&mut self) -> Option<i64> {
+        let base = self.atom()?;
+        if self.next_op("^").is_none() {
+            return Some(base);
+        }
+        let exponent = self.power()?;
+        base.checked_pow(exponent.try_into().ok()?)
+    }
+
+    fn atom(&mut self) -> Option<i64> {
+        let token = *self.tokens.get(self.pos)?;
+        self.pos += 1;
+        match token {
+            Token::Number(value) => Some(value),
+            Token::Op('-') => self.atom()?.checked_neg(),
+            Token::Open => {
+                let value = self.expr()?;
+                if self.tokens.get(self.pos) != Some(&Token::Close) {
+                    return None;
+                }
+                self.pos += 1;
+                Some(value)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Reads a coin's value from its description, e.g. "It has two dots on one side."
+/// or "It has a pentagon on one side."
+pub(crate) fn coin_value(description: &str) -> Option<i64> {
+    const NUMBERS: [&str; 11] = [
+        "zero", "one", "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten",
+    ];
+    const SHAPES: [&str; 6] = [
+        "triangle", "square", "pentagon", "hexagon", "heptagon", "octagon",
+    ];
+    let (_, markings) = description.split_once("It has ")?;
+    let mut words = markings.split_whitespace();
+    let first = words.next()?;
+    if first == "a" || first == "an" {
+        let shape = words.next()?;
+        return (SHAPES.iter().position(|&name| name == shape)).map(|sides| sides as i64 + 3);
+    }
+    let number = NUMBERS.iter().position(|&name| name == first);
+    first.parse().ok().or(number.map(|value| value as i64))
+}
+
+/// The coins in hand and the monument they go into
+pub(crate) struct CoinPuzzle {
+    pub(crate) equation: Equation,
+    /// Each coin's name and value
+    pub(crate) coins: Vec<(String, i64)>,
+}
+
+impl CoinPuzzle {
+    /// The first room, nearest first, whose description holds an equation
+    pub(crate) fn find_monument(map: &WorldMap) -> Option<&MapRoom> {
+        let mut rooms = map.rooms.iter();
+        rooms.find(|room| Equation::find(&room.room.description).is_some())
+    }
+
+    /// Reads the equation and the values of the coins carried, from a game
+    /// waiting in the monument's room
+    pub(crate) fn read(game: &Game) -> Option<Self> {
+        let equation = Equation::find(&game.look().description)?;
+        let coins = (game.inventory().into_iter())
+            .filter(|item| item.ends_with(" coin"))
+            .map(|coin| {
+                let description = game.clone().send(&format!("look {coin}"));
+                Some((coin, coin_value(&description.text)?))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self { equation, coins })
+    }
+
+    /// The coins in the order they need to be used
+    pub(crate) fn solve(&self) -> Option<Vec<String>> {
+        let blanks = self.equation.blanks();
+        let order = (self.coins.iter()).permutations(blanks).find(|order| {
+            let values = order.iter().map(|(_, value)| *value).collect_vec();
+            self.equation.holds(&values)
+        })?;
+        Some(order.into_iter().map(|(coin, _)| coin.clone()).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_solve_equation() {
+        let equation = Equation::parse("_ + _ * _^2 + _^3 - _ = 399").unwrap();
+        assert_eq!(equation.blanks(), 5);
+        assert!(equation.holds(&[9, 2, 5, 7, 3]));
+        assert!(!equation.holds(&[2, 9, 5, 7, 3]));
+        let partial = Equation::parse("9 + _ * (_ - 1) / 2 = 29").unwrap();
+        assert!(partial.holds(&[4, 11]));
+        assert_eq!(Equation::parse("It reads:"), None);
+        // Overflowing sums and differences don't hold, rather than panicking
+        let sum = Equation::parse("_^62 + _^62 = 0").unwrap();
+        assert!(!sum.holds(&[2, 2]));
+        let difference = Equation::parse("0 - _^62 - _^62 - _ = 0").unwrap();
+        assert!(!difference.holds(&[2, 2, 1]));
+
+        let coins = [
+            (
+                "red coin",
+                "This coin is made of a red metal.  It has two dots on one side.",
+            ),
+            (
+                "blue coin",
+                "This coin is made of a blue metal.  It has nine dots on one side.",
+            ),
+            (
+                "shiny coin",
+                "This coin is somehow still quite shiny.  It has a pentagon on one side.",
+            ),
+            (
+                "concave coin",
+                "This coin is slightly rounded.  It has seven dots on one side.",
+            ),
+            (
+                "corroded coin",
+                "This coin is somewhat corroded.  It has a triangle on one side.",
+            ),
+        ];
+        let puzzle = CoinPuzzle {
+            equation,
+            coins: (coins.iter())
+                .map(|(coin, description)| (coin.to_string(), coin_value(description).unwrap()))
+                .collect(),
+        };
+        assert_eq!(
+            puzzle.solve().unwrap(),
+            vec![
+                "blue coin",
+                "red coin",
+                "shiny coin",
+                "concave coin",
+                "corroded coin"
+            ]
+        );
+    }
+}
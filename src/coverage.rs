@@ -0,0 +1,123 @@
+use std::{fs, ops::Range};
+
+use itertools::Itertools;
+
+use crate::trace::parse_range;
+
+/// The set of addresses where an instruction has been executed. Saved as one
+/// `start..end` range per line, so files can be merged and read by hand.
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct Coverage {
+    executed: Vec<bool>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self {
+            executed: vec![false; 32768],
+        }
+    }
+}
+
+impl Coverage {
+    pub(crate) fn load(path: &str) -> Self {
+        let Ok(text) = fs::read_to_string(path) else {
+            panic!("Failed to read file: {path}");
+        };
+        Self::parse(&text).unwrap_or_else(|err| panic!("Failed to parse {path}: {err}"))
+    }
+
+    pub(crate) fn parse(text: &str) -> Result<Self, String> {
+        let mut coverage = Self::default();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let range = parse_range(line)?;
+            if range.end > 32768 {
+                return Err(format!("address out of bounds: {line}"));
+            }
+            for addr in range {
+                coverage.record(addr);
+            }
+        }
+        Ok(coverage)
+    }
+
+    /// Adds this run's coverage to what is already in the file, if anything
+    pub(crate) fn save_merged(&self, path: &str) {
+        let mut merged = self.clone();
+        if fs::metadata(path).is_ok() {
+            merged.merge(&Self::load(path));
+        }
+        merged.save(path);
+    }
+
+    pub(crate) fn save(&self, path: &str) {
+        let mut text = String::from("# executed address ranges\n");
+        for range in self.ranges() {
+            text += &format!("{}..{}\n", range.start, range.end);
+        }
+        fs::write(path, text).unwrap_or_else(|_| panic!("Failed to write file: {path}"));
+    }
+
+    pub(crate) fn record(&mut self, pc: u16) {
+        self.executed[pc as usize] = true;
+    }
+
+    /// Records every word of an instruction, up to the end of memory
+    pub(crate) fn record_instruction(&mut self, pc: u16, size: u16) {
+        let end = (pc as usize + size as usize).min(self.executed.len());
+        for executed in &mut self.executed[(pc as usize).min(end)..end] {
+            *executed = true;
+        }
+    }
+
+    pub(crate) fn contains(&self, addr: u16) -> bool {
+        self.executed.get(addr as usize).copied().unwrap_or(false)
+    }
+
+    pub(crate) fn merge(&mut self, other: &Self) {
+        for (executed, other) in self.executed.iter_mut().zip(&other.executed) {
+            *executed |= other;
+        }
+    }
+
+    /// Number of addresses executed
+    pub(crate) fn len(&self) -> usize {
+        self.executed.iter().filter(|&&executed| executed).count()
+    }
+
+    fn ranges(&self) -> Vec<Range<u16>> {
+        (0..self.executed.len() as u16)
+            .filter(|&addr| self.contains(addr))
+            .map(|addr| addr..addr + 1)
+            .coalesce(|a, b| {
+                if a.end == b.start {
+                    Ok(a.start..b.end)
+                } else {
+                    Err((a, b))
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_merge() {
+        let mut coverage = Coverage::parse("# comment\n0..3\n10..11\n").unwrap();
+        coverage.merge(&Coverage::parse("3..5\n").unwrap());
+        assert_eq!(coverage.ranges(), vec![0..5, 10..11]);
+        assert_eq!(coverage.len(), 6);
+        assert!(Coverage::parse("0..40000").is_err());
+
+        let mut coverage = Coverage::default();
+        coverage.record_instruction(32766, 4);
+        coverage.record_instruction(20, 3);
+        assert_eq!(coverage.ranges(), vec![20..23, 32766..32768]);
+    }
+}
@@ -0,0 +1,635 @@
+use std::{
+    collections::HashSet,
+    io::{stdin, stdout, BufRead, BufReader, Write},
+    sync::mpsc::{channel, Receiver, TryRecvError},
+    thread,
+};
+
+use itertools::Itertools;
+use serde_json::{json, Value};
+
+use crate::{
+    debugger::Debugger, instructions::parse, side_effects::BufferedSideEffects,
+    symbols::SymbolKind, VM,
+};
+
+/// How many instructions to run between checks for new requests
+const BATCH_SIZE: usize = 10_000;
+
+const THREAD_ID: u64 = 1;
+const REGISTERS_REF: u64 = 1;
+const STACK_REF: u64 = 2;
+const MEMORY_REF: u64 = 3;
+
+/// Why the VM stopped, reported to the client in a `stopped` event
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum StopReason {
+    Entry,
+    Step,
+    Breakpoint,
+    Pause,
+    Input,
+}
+
+impl StopReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            StopReason::Entry => "entry",
+            StopReason::Step => "step",
+            StopReason::Breakpoint => "breakpoint",
+            StopReason::Pause | StopReason::Input => "pause",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Stopped(StopReason),
+    Running,
+    /// Run a single instruction
+    Stepping,
+    /// Run until the shadow call stack is shallower than the given depth
+    SteppingOut(usize),
+    Exited,
+}
+
+/// A Debug Adapter Protocol server on stdin/stdout. The game's output is sent
+/// to the client's debug console, and anything typed into the console is sent
+/// to the game as a line of input.
+pub(crate) struct DapServer<'a> {
+    debugger: &'a mut Debugger,
+    vm: &'a mut VM,
+    out: &'a mut dyn Write,
+    side_effects: BufferedSideEffects,
+    mode: Mode,
+    stop_on_entry: bool,
+    /// Set when resuming, so a breakpoint at the current pc doesn't trigger again
+    skip_breakpoint: bool,
+    function_breakpoints: HashSet<u16>,
+    instruction_breakpoints: HashSet<u16>,
+    seq: u64,
+}
+
+impl<'a> DapServer<'a> {
+    pub(crate) fn serve(debugger: &'a mut Debugger, vm: &'a mut VM) {
+        let mut out = stdout();
+        let mut server = DapServer::new(debugger, vm, &mut out);
+        server.run(spawn_reader(BufReader::new(stdin())));
+    }
+
+    fn new(debugger: &'a mut Debugger, vm: &'a mut VM, out: &'a mut dyn Write) -> Self {
+        DapServer {
+            debugger,
+            vm,
+            out,
+            side_effects: BufferedSideEffects::default(),
+            mode: Mode::Stopped(StopReason::Entry),
+            stop_on_entry: false,
+            skip_breakpoint: false,
+            function_breakpoints: HashSet::new(),
+            instruction_breakpoints: HashSet::new(),
+            seq: 0,
+        }
+    }
+
+    fn run(&mut self, requests: Receiver<Value>) {
+        loop {
+            let request = match self.mode {
+                Mode::Running | Mode::Stepping | Mode::SteppingOut(_) => {
+                    match requests.try_recv() {
+                        Ok(request) => Some(request),
+                        Err(TryRecvError::Empty) => None,
+                        Err(TryRecvError::Disconnected) => return,
+                    }
+                }
+                _ => match requests.recv() {
+                    Ok(request) => Some(request),
+                    Err(_) => return,
+                },
+            };
+            if let Some(request) = request {
+                if !self.handle(&request) {
+                    return;
+                }
+            }
+            if self.is_running() {
+                self.execute(BATCH_SIZE);
+            }
+        }
+    }
+
+    /// Returns false once the client disconnects
+    fn handle(&mut self, request: &Value) -> bool {
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+        let body = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsFunctionBreakpoints": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsDisassembleRequest": true,
+                "supportsSteppingGranularity": true,
+            })),
+            "launch" | "attach" => self.launch(args),
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.stop(StopReason::Entry);
+                } else {
+                    self.mode = Mode::Running;
+                }
+                Ok(json!({}))
+            }
+            "setBreakpoints" => {
+                // There is no source to set breakpoints in
+                let count = args["breakpoints"].as_array().map_or(0, Vec::len);
+                Ok(json!({ "breakpoints": vec![json!({ "verified": false }); count] }))
+            }
+            "setExceptionBreakpoints" => Ok(json!({})),
+            "setFunctionBreakpoints" => Ok(self.set_breakpoints(args, "name", false)),
+            "setInstructionBreakpoints" => {
+                Ok(self.set_breakpoints(args, "instructionReference", true))
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "vm" }] })),
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false },
+                { "name": "Stack", "variablesReference": STACK_REF, "expensive": false },
+                { "name": "Memory", "variablesReference": MEMORY_REF, "expensive": false },
+            ]})),
+            "variables" => Ok(self.variables(args)),
+            "continue" => {
+                self.resume(Mode::Running);
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" | "stepIn" => {
+                self.resume(Mode::Stepping);
+                Ok(json!({}))
+            }
+            "stepOut" => {
+                self.resume(Mode::SteppingOut(self.debugger.call_stack().len()));
+                Ok(json!({}))
+            }
+            "pause" => {
+                if self.is_running() {
+                    self.stop(StopReason::Pause);
+                }
+                Ok(json!({}))
+            }
+            "disassemble" => self.disassemble(args),
+            "evaluate" => self.evaluate(args),
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(json!({})));
+                return false;
+            }
+            _ => Err(format!("Unsupported request: {command}")),
+        };
+        self.respond(request, body);
+        true
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        if let Some(path) = args["replay"].as_str() {
+            let Ok(replay) = std::fs::read_to_string(path) else {
+                return Err(format!("Failed to open file for reading: {path}"));
+            };
+            self.side_effects.send(&replay);
+        }
+        self.event("initialized", json!({}));
+        Ok(json!({}))
+    }
+
+    fn is_running(&self) -> bool {
+        matches!(
+            self.mode,
+            Mode::Running | Mode::Stepping | Mode::SteppingOut(_)
+        )
+    }
+
+    fn resume(&mut self, mode: Mode) {
+        if self.mode == Mode::Exited {
+            return;
+        }
+        self.mode = mode;
+        self.skip_breakpoint = true;
+    }
+
+    /// Runs up to `count` instructions, stopping early if needed
+    fn execute(&mut self, count: usize) {
+        for _ in 0..count {
+            if self.debugger.halts(self.vm) {
+                self.flush_output();
+                self.mode = Mode::Exited;
+                self.event("exited", json!({ "exitCode": 0 }));
+                self.event("terminated", json!({}));
+                return;
+            }
+            if self.debugger.reads_input(self.vm) && self.side_effects.input.is_empty() {
+                self.stop(StopReason::Input);
+                return;
+            }
+            if !self.skip_breakpoint && self.debugger.has_breakpoint(self.vm.pc) {
+                self.stop(StopReason::Breakpoint);
+                return;
+            }
+            self.skip_breakpoint = false;
+            if let Mode::SteppingOut(depth) = self.mode {
+                if self.debugger.call_stack().len() < depth {
+                    self.stop(StopReason::Step);
+                    return;
+                }
+            }
+            self.debugger.step(self.vm, &mut self.side_effects);
+            if self.mode == Mode::Stepping {
+                self.stop(StopReason::Step);
+                return;
+            }
+        }
+        self.flush_output();
+    }
+
+    fn stop(&mut self, reason: StopReason) {
+        self.flush_output();
+        self.mode = Mode::Stopped(reason);
+        let description = match reason {
+            StopReason::Input => "Waiting for input (type a command in the debug console)",
+            _ => "Paused",
+        };
+        self.event(
+            "stopped",
+            json!({
+                "reason": reason.as_str(),
+                "description": description,
+                "threadId": THREAD_ID,
+                "allThreadsStopped": true,
+            }),
+        );
+    }
+
+    fn flush_output(&mut self) {
+        if self.side_effects.output.is_empty() {
+            return;
+        }
+        let output = std::mem::take(&mut self.side_effects.output);
+        self.event("output", json!({ "category": "stdout", "output": output }));
+    }
+
+    /// Replaces one kind of breakpoint (function or instruction) with those in the request
+    fn set_breakpoints(&mut self, args: &Value, field: &str, instruction: bool) -> Value {
+        let requested = args["breakpoints"].as_array().cloned().unwrap_or_default();
+        let resolved = requested
+            .iter()
+            .map(|bp| {
+                let target = bp[field].as_str().unwrap_or_default();
+                let offset = bp["offset"].as_i64().unwrap_or(0);
+                self.debugger
+                    .symbols()
+                    .resolve(target)
+                    .and_then(|addr| u16::try_from(addr as i64 + offset).ok())
+                    .filter(|&addr| (addr as usize) < self.vm.memory.len())
+            })
+            .collect_vec();
+        let breakpoints = resolved.iter().flatten().copied().collect();
+        let before = self.all_breakpoints();
+        if instruction {
+            self.instruction_breakpoints = breakpoints;
+        } else {
+            self.function_breakpoints = breakpoints;
+        }
+        for addr in &before {
+            self.debugger.remove_breakpoint(*addr);
+        }
+        for addr in self.all_breakpoints() {
+            self.debugger.add_breakpoint(addr);
+        }
+        let breakpoints = resolved
+            .iter()
+            .map(|addr| match addr {
+                Some(addr) => json!({
+                    "verified": true,
+                    "instructionReference": addr.to_string(),
+                }),
+                None => json!({ "verified": false, "message": "Unknown address or symbol" }),
+            })
+            .collect_vec();
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn all_breakpoints(&self) -> HashSet<u16> {
+        self.function_breakpoints
+            .union(&self.instruction_breakpoints)
+            .copied()
+            .collect()
+    }
+
+    fn stack_trace(&self) -> Value {
+        let symbols = self.debugger.symbols();
+        let frame = |id: usize, addr: u16| {
+            json!({
+                "id": id,
+                "name": symbols.label(addr).unwrap_or_else(|| addr.to_string()),
+                "line": 0,
+                "column": 0,
+                "instructionPointerReference": addr.to_string(),
+            })
+        };
+        let mut frames = vec![frame(0, self.vm.pc)];
+        for (depth, caller) in self.debugger.call_stack().iter().rev().enumerate() {
+            frames.push(frame(depth + 1, caller.call_site));
+        }
+        json!({ "stackFrames": frames, "totalFrames": frames.len() })
+    }
+
+    fn variables(&self, args: &Value) -> Value {
+        let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        let symbols = self.debugger.symbols();
+        let variables = match args["variablesReference"].as_u64() {
+            Some(REGISTERS_REF) => {
+                let mut variables = vec![variable("pc".into(), symbols.describe(self.vm.pc))];
+                for (reg, value) in self.vm.registers.iter().enumerate() {
+                    variables.push(variable(format!("reg{reg}"), value.to_string()));
+                }
+                variables
+            }
+            Some(STACK_REF) => (self.vm.stack.iter().rev().enumerate())
+                .map(|(depth, value)| variable(format!("[{depth}]"), value.to_string()))
+                .collect(),
+            Some(MEMORY_REF) => symbols
+                .iter()
+                .filter(|symbol| symbol.kind != SymbolKind::Function)
+                .map(|symbol| {
+                    let start = symbol.address as usize;
+                    let value = if symbol.kind == SymbolKind::Str {
+                        let len = self.vm.memory[start] as usize;
+                        let text: String = (self.vm.memory[start + 1..].iter().take(len))
+                            .filter_map(|&c| char::from_u32(c as u32))
+                            .collect();
+                        format!("{text:?}")
+                    } else {
+                        let end = (start + symbol.length as usize).min(self.vm.memory.len());
+                        self.vm.memory[start..end].iter().join(", ")
+                    };
+                    variable(symbol.name.clone(), value)
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        json!({ "variables": variables })
+    }
+
+    fn disassemble(&self, args: &Value) -> Result<Value, String> {
+        let reference = args["memoryReference"].as_str().unwrap_or_default();
+        let Some(base) = self.debugger.symbols().resolve(reference) else {
+            return Err(format!("Invalid memory reference: {reference}"));
+        };
+        let base = base as i64 + args["offset"].as_i64().unwrap_or(0);
+        let offset = args["instructionOffset"].as_i64().unwrap_or(0);
+        let count = args["instructionCount"].as_i64().unwrap_or(0).max(0) as usize;
+        // Instructions are at most 4 words, so decode from far enough back to
+        // cover a negative offset, then find the base in the decoded sequence
+        let mut addr = (base + offset.min(0) * 4).max(0);
+        let mut decoded = Vec::new();
+        let mut base_index = None;
+        let done =
+            |decoded: &Vec<_>, index: i64| decoded.len() as i64 >= index + offset + count as i64;
+        while !base_index.is_some_and(|index| done(&decoded, index)) {
+            if addr >= base && base_index.is_none() {
+                base_index = Some(decoded.len() as i64);
+            }
+            let (text, size) = match parse(&self.vm.memory, addr as u16) {
+                _ if addr >= self.vm.memory.len() as i64 => ("??".into(), 1),
+                Some((instruction, size)) => (instruction.to_string(), size),
+                None => ("??".into(), 1),
+            };
+            decoded.push((addr, text));
+            addr += size as i64;
+        }
+        let start = base_index.unwrap_or(0) + offset;
+        let symbols = self.debugger.symbols();
+        let instructions = (start..start + count as i64)
+            .map(
+                |index| match usize::try_from(index).ok().and_then(|i| decoded.get(i)) {
+                    Some((addr, text)) if *addr < self.vm.memory.len() as i64 => {
+                        let mut instruction = json!({
+                            "address": addr.to_string(),
+                            "instruction": text,
+                        });
+                        if let Some(symbol) = symbols.label(*addr as u16) {
+                            instruction["symbol"] = json!(symbol);
+                        }
+                        instruction
+                    }
+                    _ => {
+                        json!({ "address": "-1", "instruction": "", "presentationHint": "invalid" })
+                    }
+                },
+            )
+            .collect_vec();
+        Ok(json!({ "instructions": instructions }))
+    }
+
+    /// Debug console input goes to the game, while watch/hover expressions are
+    /// looked up as registers or symbols
+    fn evaluate(&mut self, args: &Value) -> Result<Value, String> {
+        let expression = args["expression"].as_str().unwrap_or_default();
+        if args["context"].as_str() == Some("repl") {
+            self.side_effects.send(expression);
+            self.side_effects.send("\n");
+            if self.mode == Mode::Stopped(StopReason::Input) {
+                self.mode = Mode::Running;
+                self.event("continued", json!({ "threadId": THREAD_ID }));
+            }
+            return Ok(json!({ "result": "", "variablesReference": 0 }));
+        }
+        let value = if expression == "pc" {
+            Some(self.vm.pc)
+        } else if let Some(reg) = expression.strip_prefix("reg") {
+            (reg.parse::<usize>().ok())
+                .and_then(|reg| self.vm.registers.get(reg))
+                .copied()
+        } else {
+            (self.debugger.symbols().resolve(expression))
+                .and_then(|addr| self.vm.memory.get(addr as usize))
+                .copied()
+        };
+        match value {
+            Some(value) => Ok(json!({ "result": value.to_string(), "variablesReference": 0 })),
+            None => Err(format!("Unknown register or symbol: {expression}")),
+        }
+    }
+
+    fn respond(&mut self, request: &Value, body: Result<Value, String>) {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": body.is_ok(),
+        });
+        match body {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response);
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let text = message.to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{text}", text.len())
+            .and_then(|_| self.out.flush())
+            .expect("Failed to write to stdout");
+    }
+}
+
+/// Reads `Content-Length` framed messages on a separate thread, so requests
+/// (e.g. pause) can arrive while the VM is running
+fn spawn_reader(mut reader: impl BufRead + Send + 'static) -> Receiver<Value> {
+    let (sender, receiver) = channel();
+    thread::spawn(move || loop {
+        let mut length = None;
+        loop {
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => (),
+            }
+            let line = line.trim();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length:") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+        let Some(length) = length else {
+            continue;
+        };
+        let mut content = vec![0; length];
+        if reader.read_exact(&mut content).is_err() {
+            return;
+        }
+        let Ok(message) = serde_json::from_slice(&content) else {
+            continue;
+        };
+        if sender.send(message).is_err() {
+            return;
+        }
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::symbols::Symbols;
+
+    fn frame(messages: &[Value]) -> Vec<u8> {
+        let mut framed = Vec::new();
+        for (seq, message) in messages.iter().enumerate() {
+            let mut message = message.clone();
+            message["seq"] = json!(seq + 1);
+            let text = message.to_string();
+            write!(framed, "Content-Length: {}\r\n\r\n{text}", text.len()).unwrap();
+        }
+        framed
+    }
+
+    fn unframe(mut framed: &str) -> Vec<Value> {
+        let mut messages = Vec::new();
+        while let Some(rest) = framed.strip_prefix("Content-Length: ") {
+            let (length, rest) = rest.split_once("\r\n\r\n").unwrap();
+            let (text, rest) = rest.split_at(length.parse().unwrap());
+            messages.push(serde_json::from_str(text).unwrap());
+            framed = rest;
+        }
+        assert!(framed.is_empty(), "Unframed output: {framed:?}");
+        messages
+    }
+
+    fn request(command: &str, arguments: Value) -> Value {
+        json!({ "type": "request", "command": command, "arguments": arguments })
+    }
+
+    #[test]
+    fn test_requests() {
+        let symbols = Symbols::parse("confirm = 6027 fn\nfirst = 0 u16 2").unwrap();
+        let mut debugger = Debugger::new(symbols);
+        let mut vm = VM::challenge();
+        vm.registers[3] = 7;
+        let input = frame(&[
+            request("initialize", json!({})),
+            request("launch", json!({ "stopOnEntry": true })),
+            request(
+                "setFunctionBreakpoints",
+                json!({ "breakpoints": [{ "name": "confirm" }, { "name": "nowhere" }] }),
+            ),
+            request("configurationDone", json!({})),
+            request(
+                "disassemble",
+                json!({ "memoryReference": "first", "offset": 1, "instructionCount": 2 }),
+            ),
+            request("variables", json!({ "variablesReference": REGISTERS_REF })),
+            request("variables", json!({ "variablesReference": MEMORY_REF })),
+            request(
+                "evaluate",
+                json!({ "expression": "reg3", "context": "hover" }),
+            ),
+            request(
+                "evaluate",
+                json!({ "expression": "look", "context": "repl" }),
+            ),
+            request("disconnect", json!({})),
+        ]);
+        let mut out = Vec::new();
+        let mut server = DapServer::new(&mut debugger, &mut vm, &mut out);
+        server.run(spawn_reader(Cursor::new(input)));
+        let sent = String::from_utf8(std::mem::take(&mut server.side_effects.input).into());
+        assert_eq!(sent.unwrap(), "look\n");
+        assert!(server.debugger.has_breakpoint(6027));
+
+        let messages = unframe(std::str::from_utf8(&out).unwrap());
+        let response = |command: &str, nth: usize| {
+            let mut responses = messages
+                .iter()
+                .filter(|message| message["type"] == "response" && message["command"] == command);
+            let response = responses.nth(nth).unwrap();
+            assert_eq!(response["success"], true, "{response}");
+            response["body"].clone()
+        };
+        assert_eq!(
+            response("setFunctionBreakpoints", 0)["breakpoints"],
+            json!([
+                { "verified": true, "instructionReference": "6027" },
+                { "verified": false, "message": "Unknown address or symbol" },
+            ])
+        );
+        let stopped = messages
+            .iter()
+            .find(|message| message["event"] == "stopped");
+        assert_eq!(stopped.unwrap()["body"]["reason"], "entry");
+        assert_eq!(
+            response("disassemble", 0)["instructions"],
+            json!([
+                { "address": "1", "instruction": "Noop", "symbol": "first+1" },
+                { "address": "2", "instruction": "Out [87 'W']" },
+            ])
+        );
+        let registers = response("variables", 0)["variables"].clone();
+        assert_eq!(registers[0]["value"], "0 <first>");
+        assert_eq!(
+            registers[4],
+            json!({ "name": "reg3", "value": "7", "variablesReference": 0 })
+        );
+        let memory = response("variables", 1)["variables"].clone();
+        assert_eq!(memory[0]["name"], "first");
+        assert_eq!(response("evaluate", 0)["result"], "7");
+        assert_eq!(response("evaluate", 1)["result"], "");
+    }
+}
@@ -1,35 +1,86 @@
 use std::{
     collections::{HashMap, HashSet},
     io::{stdin, stdout, BufRead, Write},
+    process::exit,
 };
 
 use itertools::Itertools;
 
 use crate::{
+    call_cache::CallCache,
+    codes::CodeExtractor,
+    coverage::Coverage,
     instructions::{
-        parse, Instruction, Noop,
+        parse, Call, Halt, In, Instruction, Noop,
         Operand::{Literal, Reg},
-        Set,
+        Out, Ret, Set,
     },
+    lift::Routine,
+    profiler::Profiler,
+    scripting::{Outcome, Resume, Scripting},
     side_effects::{FileBackedEffects, SideEffects},
+    symbols::{SymbolKind, Symbols},
+    trace::{TraceRecord, TraceSink},
     VM,
 };
 
+/// An entry in the shadow call stack, tracked alongside `Call`/`Ret`
+#[derive(Clone, Copy)]
+pub(crate) struct Frame {
+    pub(crate) call_site: u16,
+    pub(crate) target: u16,
+}
+
 pub(crate) struct Debugger {
     breakpoints: HashSet<u16>,
     single_step: bool,
     break_on_exhaust: bool,
     trace: bool,
     memory_patches: HashMap<u16, Box<dyn Instruction>>,
+    bp_commands: HashMap<u16, Vec<String>>,
+    /// Created on first use
+    scripting: Option<Scripting>,
+    /// Game input queued by scripts
+    pending_input: String,
+    /// Whether input from scripts is recorded to the replay file
+    record_input: bool,
+    symbols: Symbols,
+    call_stack: Vec<Frame>,
+    /// Number of instructions executed so far
+    steps: u64,
+    trace_sink: Option<TraceSink>,
+    profiler: Option<Profiler>,
+    /// Addresses executed this session, and the file to merge them into
+    coverage: Option<(String, Coverage)>,
+    codes: Option<CodeExtractor>,
+    call_cache: Option<CallCache>,
+    /// The teleporter's confirmation, swapped in for calls to it instead of patching around them
+    confirmation: Option<Routine>,
+    /// Set in the eighth register once the self-test has passed
+    eighth_register: Option<u16>,
 }
 
 impl Debugger {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(symbols: Symbols) -> Self {
         Self {
             breakpoints: HashSet::new(),
             single_step: false,
             break_on_exhaust: true,
             trace: false,
+            bp_commands: HashMap::new(),
+            scripting: None,
+            pending_input: String::new(),
+            record_input: false,
+            symbols,
+            call_stack: Vec::new(),
+            steps: 0,
+            trace_sink: None,
+            profiler: None,
+            coverage: None,
+            codes: None,
+            call_cache: None,
+            confirmation: None,
+            eighth_register: None,
             memory_patches: [
                 (5451, Noop::new()),
                 (5483, Set::new(Reg(0), Literal(6))),
@@ -40,20 +91,195 @@ impl Debugger {
         }
     }
 
+    /// A debugger for a snapshot of the program, running it the same way:
+    /// the same patches and swapped-in confirmation, without the bookkeeping
+    pub(crate) fn snapshot(&self) -> Self {
+        let mut debugger = Self::new(self.symbols.clone());
+        debugger.memory_patches = self.memory_patches.clone();
+        debugger.confirmation = self.confirmation.clone();
+        debugger.eighth_register = self.eighth_register;
+        debugger
+    }
+
     fn instruction_at_pc(&self, vm: &VM) -> (Box<dyn Instruction>, u16) {
         let (instruction, size) =
-            parse(&vm.memory, vm.pc).expect(&format!("Invalid PC: {}", vm.pc));
+            parse(&vm.memory, vm.pc).unwrap_or_else(|| panic!("Invalid PC: {}", vm.pc));
         if let Some(instruction) = self.memory_patches.get(&vm.pc) {
             return (instruction.clone(), size);
         }
         (instruction, size)
     }
 
+    /// Whether executing the instruction at pc would halt the program
+    pub(crate) fn halts(&self, vm: &VM) -> bool {
+        let (instruction, _) = self.instruction_at_pc(vm);
+        halts(instruction.as_ref(), vm)
+    }
+
+    pub(crate) fn set_trace_sink(&mut self, sink: TraceSink) {
+        self.trace_sink = Some(sink);
+    }
+
+    pub(crate) fn set_profiler(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    pub(crate) fn set_coverage_file(&mut self, path: &str) {
+        self.coverage = Some((path.into(), Coverage::default()));
+    }
+
+    pub(crate) fn set_code_extractor(&mut self, codes: CodeExtractor) {
+        self.codes = Some(codes);
+    }
+
+    pub(crate) fn set_call_cache(&mut self, cache: CallCache) {
+        self.call_cache = Some(cache);
+    }
+
+    /// Drops the patches around the teleporter's confirmation, and instead
+    /// sets the eighth register to `magic` and swaps each call to the
+    /// confirmation for the routine lifted out of the binary, which runs its
+    /// recursion in one step. If calls to it are memoized, the VM runs the
+    /// real routine under the cache instead.
+    pub(crate) fn swap_confirmation(&mut self, memory: &[u16], magic: u16) {
+        let Some(routine) = Routine::find_confirmation(memory) else {
+            panic!("Failed to find the teleporter's confirmation routine");
+        };
+        self.memory_patches.clear();
+        self.confirmation = Some(routine);
+        self.eighth_register = Some(magic);
+    }
+
+    /// Flushes any output files, since halting exits the process
+    fn finish(&mut self) {
+        if let Some(sink) = &mut self.trace_sink {
+            sink.flush();
+        }
+        if let Some(profiler) = self.profiler.take() {
+            profiler.finish(&self.symbols);
+        }
+        if let Some((path, coverage)) = self.coverage.take() {
+            coverage.save_merged(&path);
+        }
+        if let Some(mut codes) = self.codes.take() {
+            codes.finish();
+        }
+        if let Some(cache) = self.call_cache.take() {
+            cache.report();
+        }
+    }
+
+    /// Whether the instruction at pc reads a character of input
+    pub(crate) fn reads_input(&self, vm: &VM) -> bool {
+        self.instruction_at_pc(vm).0.opcode() == In::OPCODE
+    }
+
+    pub(crate) fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    /// The shadow call stack, outermost call first
+    pub(crate) fn call_stack(&self) -> &[Frame] {
+        &self.call_stack
+    }
+
+    pub(crate) fn has_breakpoint(&self, addr: u16) -> bool {
+        self.breakpoints.contains(&addr)
+    }
+
+    /// Returns false if the breakpoint was already present
+    pub(crate) fn add_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.insert(addr)
+    }
+
+    /// Returns false if there was no such breakpoint
+    pub(crate) fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    pub(crate) fn step(&mut self, vm: &mut VM, side_effects: &mut dyn SideEffects) {
+        let (instruction, size) = self.instruction_at_pc(vm);
+        if instruction.opcode() == In::OPCODE {
+            // The self-test insists on the eighth register being zero
+            if let Some(value) = self.eighth_register.take() {
+                vm.registers[7] = value;
+            }
+        }
+        if let Some(cache) = &mut self.call_cache {
+            if cache.intercept(vm, instruction.as_ref(), size) {
+                self.steps += 1;
+                return;
+            }
+        }
+        // Calls being memoized run for real, so the cache sees them through
+        let memoized = self.call_cache.as_ref().map(CallCache::entry);
+        if let Some(routine) = (self.confirmation.as_mut()).filter(|r| Some(r.entry) != memoized) {
+            if instruction.opcode() == Call::OPCODE
+                && instruction.operands()[0].value(vm) == routine.entry
+            {
+                if let Err(err) = routine.run(vm) {
+                    panic!("Failed to run the teleporter's confirmation: {err}");
+                }
+                vm.pc += size;
+                self.steps += 1;
+                return;
+            }
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(vm.pc);
+        }
+        if let Some(codes) = &mut self.codes {
+            if instruction.opcode() == Out::OPCODE {
+                let value = instruction.operands()[0].value(vm);
+                codes.print(char::from_u32(value as u32).unwrap_or('?'));
+            }
+        }
+        if let Some((_, coverage)) = &mut self.coverage {
+            coverage.record_instruction(vm.pc, size);
+        }
+        let mut record = match &self.trace_sink {
+            Some(sink) if sink.includes(vm.pc) => {
+                Some(TraceRecord::before(self.steps, vm, instruction.as_ref()))
+            }
+            _ => None,
+        };
+        if halts(instruction.as_ref(), vm) {
+            if let (Some(sink), Some(record)) = (&mut self.trace_sink, record.take()) {
+                sink.write(&record);
+            }
+            self.finish();
+        }
+        let call_site = vm.pc;
+        vm.pc += size;
+        instruction.execute(vm, side_effects);
+        self.steps += 1;
+        if let (Some(sink), Some(mut record)) = (&mut self.trace_sink, record) {
+            record.after(vm, instruction.as_ref());
+            sink.write(&record);
+        }
+        match instruction.opcode() {
+            Call::OPCODE => {
+                self.call_stack.push(Frame {
+                    call_site,
+                    target: vm.pc,
+                });
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.enter(vm.pc);
+                }
+            }
+            Ret::OPCODE => {
+                self.call_stack.pop();
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.exit();
+                }
+            }
+            _ => (),
+        }
+    }
+
     pub(crate) fn run(&mut self, vm: &mut VM, side_effects: &mut dyn SideEffects) {
         loop {
-            let (instruction, size) = self.instruction_at_pc(vm);
-            vm.pc += size;
-            instruction.execute(vm, side_effects);
+            self.step(vm, side_effects);
         }
     }
 
@@ -63,22 +289,41 @@ impl Debugger {
                 self.break_on_exhaust = false;
                 self.single_step = true;
             }
-            if self.breakpoints.contains(&vm.pc) {
+            let hit_breakpoint = self.breakpoints.contains(&vm.pc);
+            if hit_breakpoint {
                 self.single_step = true;
             }
             if self.single_step || self.trace {
-                let (instruction, _) = self.instruction_at_pc(vm);
-                println!("{}: {instruction}", vm.pc);
+                self.print_instruction(vm);
+            }
+            let mut resumed = false;
+            if hit_breakpoint {
+                resumed |= self.run_bp_commands(vm);
+                resumed |= self.run_bp_callback(vm);
             }
-            if self.single_step {
+            if self.single_step && !resumed {
                 self.shell(vm);
             }
-            let (instruction, size) = self.instruction_at_pc(vm);
-            vm.pc += size;
-            instruction.execute(vm, side_effects);
+            self.queue_input(side_effects);
+            self.step(vm, side_effects);
         }
     }
 
+    /// Hands input sent by scripts to the game
+    fn queue_input(&mut self, side_effects: &mut FileBackedEffects) {
+        let input = std::mem::take(&mut self.pending_input);
+        side_effects.queue(&input, self.record_input);
+    }
+
+    fn print_instruction(&self, vm: &VM) {
+        let (instruction, _) = self.instruction_at_pc(vm);
+        let annotation = self.symbols.annotate(instruction.address_operands());
+        println!(
+            "{}: {instruction}{annotation}",
+            self.symbols.describe(vm.pc)
+        );
+    }
+
     fn set_bp_command<'a, 'b>(&'a mut self, operands: impl Iterator<Item = &'b str>) {
         let operands = operands.collect_vec();
         if operands.is_empty() {
@@ -87,13 +332,13 @@ impl Debugger {
             } else {
                 println!("Current breakpoints:");
                 for bp in self.breakpoints.iter().sorted() {
-                    println!("{bp}")
+                    println!("{}", self.symbols.describe(*bp))
                 }
             }
         } else {
             for operand in operands {
-                let Ok(addr) = operand.parse() else {
-                    println!("Expected integer, found: {operand}");
+                let Some(addr) = self.symbols.resolve(operand) else {
+                    println!("Expected integer or symbol, found: {operand}");
                     return;
                 };
                 if !self.breakpoints.insert(addr) {
@@ -109,8 +354,8 @@ impl Debugger {
             println!("Expected format: target value");
             return;
         };
-        let Ok(value) = value.parse() else {
-            println!("Expected integer, found: {value}");
+        let Some(value) = self.symbols.resolve(value) else {
+            println!("Expected integer or symbol, found: {value}");
             return;
         };
         if target == "pc" {
@@ -125,7 +370,7 @@ impl Debugger {
                 return;
             }
             vm.registers[reg] = value;
-        } else if let Ok(addr) = target.parse::<usize>() {
+        } else if let Some(addr) = self.symbols.resolve(target).map(usize::from) {
             if addr >= vm.memory.len() {
                 println!("Address out of bounds: {addr} (max {})", vm.memory.len());
                 return;
@@ -136,56 +381,358 @@ impl Debugger {
         }
     }
 
-    fn shell(&mut self, vm: &mut VM) {
-        let get_line = || {
-            print!("# ");
-            stdout().flush().expect("Failed to flush stdout");
-            let reader = stdin();
-            let mut handle = reader.lock();
-            let mut line = String::new();
-            handle.read_line(&mut line).expect("Failed to read line");
-            line.trim().to_string()
+    fn examine_command<'a, 'b>(&'a self, vm: &'a VM, operands: impl Iterator<Item = &'b str>) {
+        let operands = operands.collect_vec();
+        let Some(target) = operands.first() else {
+            println!("Expected format: address [count]");
+            return;
+        };
+        let Some(addr) = self.symbols.resolve(target) else {
+            println!("Expected integer or symbol, found: {target}");
+            return;
+        };
+        let symbol = self.symbols.get(target);
+        let count = match operands.get(1) {
+            Some(count) => {
+                let Ok(count) = count.parse::<u16>() else {
+                    println!("Expected integer, found: {count}");
+                    return;
+                };
+                count
+            }
+            None => symbol.map_or(1, |symbol| symbol.length),
+        };
+        if symbol.map(|symbol| symbol.kind) == Some(SymbolKind::Str) && operands.len() == 1 {
+            let len = vm.memory[addr as usize] as usize;
+            let text: String = vm.memory[addr as usize + 1..]
+                .iter()
+                .take(len)
+                .filter_map(|&c| char::from_u32(c as u32))
+                .collect();
+            println!("{}: {text:?}", self.symbols.describe(addr));
+            return;
+        }
+        for addr in addr..addr.saturating_add(count).min(vm.memory.len() as u16) {
+            println!(
+                "{}: {}",
+                self.symbols.describe(addr),
+                vm.memory[addr as usize]
+            );
+        }
+    }
+
+    fn backtrace_command(&self, vm: &VM) {
+        println!("#0 {}", self.symbols.describe(vm.pc));
+        for (depth, frame) in self.call_stack.iter().rev().enumerate() {
+            println!(
+                "#{} {} (call to {})",
+                depth + 1,
+                self.symbols.describe(frame.call_site),
+                self.symbols.describe(frame.target),
+            );
+        }
+    }
+
+    /// Runs the commands in a script file: each line is a shell command, and
+    /// `commands <bp>` ... `end` blocks attach commands to a breakpoint
+    pub(crate) fn source(&mut self, vm: &mut VM, path: &str) {
+        let Ok(script) = std::fs::read_to_string(path) else {
+            println!("Failed to open file for reading: {path}");
+            return;
+        };
+        let mut lines = script.lines().map(str::trim);
+        while let Some(line) = lines.next() {
+            if let Some(bp) = line.strip_prefix("commands ") {
+                let block = lines.by_ref().take_while(|line| *line != "end");
+                self.set_bp_commands(bp.trim(), block.map(String::from).collect());
+            } else {
+                self.execute(vm, line);
+            }
+        }
+    }
+
+    fn set_bp_commands(&mut self, bp: &str, commands: Vec<String>) {
+        let Some(addr) = self.symbols.resolve(bp) else {
+            println!("Expected integer or symbol, found: {bp}");
+            return;
         };
+        self.breakpoints.insert(addr);
+        let commands = commands
+            .into_iter()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect_vec();
+        if commands.is_empty() {
+            self.bp_commands.remove(&addr);
+        } else {
+            self.bp_commands.insert(addr, commands);
+        }
+    }
+
+    /// Runs the commands attached to the breakpoint at pc, returning true if they resumed execution
+    fn run_bp_commands(&mut self, vm: &mut VM) -> bool {
+        let Some(commands) = self.bp_commands.get(&vm.pc).cloned() else {
+            return false;
+        };
+        commands.iter().any(|line| self.execute(vm, line))
+    }
+
+    /// Runs the script callback for the breakpoint at pc, returning true if it resumed execution
+    fn run_bp_callback(&mut self, vm: &mut VM) -> bool {
+        let Some(scripting) = &mut self.scripting else {
+            return false;
+        };
+        if !scripting.has_callback(vm.pc) {
+            return false;
+        }
+        let outcome = scripting.on_breakpoint(vm);
+        self.apply_outcome(outcome)
+    }
+
+    /// Evaluates a Rhai script, returning true if it resumed execution
+    fn eval_script(&mut self, vm: &mut VM, code: &str) -> bool {
+        let symbols = &self.symbols;
+        let scripting = (self.scripting).get_or_insert_with(|| Scripting::new(symbols.clone()));
+        let outcome = scripting.eval(vm, code);
+        self.apply_outcome(outcome)
+    }
+
+    fn apply_outcome(&mut self, outcome: Outcome) -> bool {
+        self.breakpoints.extend(outcome.breakpoints);
+        self.pending_input.push_str(&outcome.input);
+        match outcome.resume {
+            Some(Resume::Step) => self.single_step = true,
+            Some(Resume::Continue) => self.single_step = false,
+            None => return false,
+        }
+        true
+    }
+
+    fn shell(&mut self, vm: &mut VM) {
         loop {
-            let line = get_line();
-            let mut operands = line.split(' ');
-            let Some(command) = operands.next() else {
-                continue;
+            let Some(line) = read_line("# ") else {
+                // Nothing left to read, so there's no way to resume
+                self.finish();
+                exit(0);
             };
-            match command {
-                "s" => {
-                    self.single_step = true;
-                    break;
-                }
-                "g" => {
-                    self.single_step = false;
-                    break;
+            if let Some(bp) = line.strip_prefix("commands ") {
+                let mut commands = Vec::new();
+                while let Some(line) = read_line("> ") {
+                    if line == "end" {
+                        break;
+                    }
+                    commands.push(line);
                 }
-                "bp" => self.set_bp_command(operands),
-                "regs" => {
-                    println!("pc:   {}", vm.pc);
-                    for reg in 0..8 {
-                        println!("reg{reg}: {}", vm.registers[reg]);
+                self.set_bp_commands(bp.trim(), commands);
+            } else if line == "rhai" {
+                let mut code = String::new();
+                while let Some(line) = read_line("> ") {
+                    if line == "end" {
+                        break;
                     }
+                    code.push_str(&line);
+                    code.push('\n');
                 }
-                "set" => self.set_command(vm, operands),
-                "trace" => {
-                    self.single_step = false;
-                    self.trace = true;
+                if self.eval_script(vm, &code) {
                     break;
                 }
-                "help" => println!("Available commands:\n\
-                    s                    - step a single instruction\n\
-                    g                    - resume program execution\n\
-                    bp                   - list the current breakpoints\n\
-                    bp <address>...      - toggle a breakpoint at the given address (or addresses)\n\
-                    regs                 - list pc and register values\n\
-                    set <target> <value> - set the target register (or pc) to the given integer value\n\
-                    trace                - resume program execution and print all instructions\
-                "),
-                "" => (),
-                _ => println!("Unknown command (try 'help')"),
+            } else if self.execute(vm, &line) {
+                break;
             }
         }
     }
+
+    /// Runs a single shell command, returning true if it resumes execution
+    fn execute(&mut self, vm: &mut VM, line: &str) -> bool {
+        let mut operands = line.split(' ');
+        let Some(command) = operands.next() else {
+            return false;
+        };
+        match command {
+            "s" => {
+                self.single_step = true;
+                return true;
+            }
+            "g" => {
+                self.single_step = false;
+                return true;
+            }
+            "bp" => self.set_bp_command(operands),
+            "regs" => {
+                println!("pc:   {}", self.symbols.describe(vm.pc));
+                for reg in 0..8 {
+                    println!("reg{reg}: {}", vm.registers[reg]);
+                }
+            }
+            "set" => self.set_command(vm, operands),
+            "x" => self.examine_command(vm, operands),
+            "bt" => self.backtrace_command(vm),
+            "rhai" => return self.eval_script(vm, &operands.join(" ")),
+            "script" => match operands.next() {
+                Some(path) => match std::fs::read_to_string(path) {
+                    Ok(code) => return self.eval_script(vm, &code),
+                    Err(_) => println!("Failed to open file for reading: {path}"),
+                },
+                None => println!("Expected format: script <file>"),
+            },
+            "source" => match operands.next() {
+                Some(path) => self.source(vm, path),
+                None => println!("Expected format: source <file>"),
+            },
+            "record" => {
+                self.record_input = !self.record_input;
+                let state = if self.record_input { "on" } else { "off" };
+                println!("Recording input from scripts to the replay: {state}");
+            }
+            "trace" => {
+                self.single_step = false;
+                self.trace = true;
+                return true;
+            }
+            "help" => println!("Available commands:\n\
+                s                    - step a single instruction\n\
+                g                    - resume program execution\n\
+                bp                   - list the current breakpoints\n\
+                bp <address>...      - toggle a breakpoint at the given address (or addresses)\n\
+                commands <address>   - set commands (one per line, finished by 'end') to run at a breakpoint\n\
+                regs                 - list pc and register values\n\
+                set <target> <value> - set the target register (or pc) to the given integer value\n\
+                x <address> [count]  - print the memory at the given address\n\
+                bt                   - print the call stack\n\
+                source <file>        - run the commands in a script file\n\
+                rhai <code>          - evaluate Rhai code (or 'rhai' alone for several lines, finished by 'end')\n\
+                script <file>        - evaluate a Rhai script file\n\
+                record               - toggle recording input sent by scripts to the replay file\n\
+                trace                - resume program execution and print all instructions\n\
+                \n\
+                Addresses and values may be integers or symbols (e.g. confirm_check+3)\
+            "),
+            // Blank lines and comments (in scripts)
+            _ if command.is_empty() || command.starts_with('#') => (),
+            _ => println!("Unknown command (try 'help')"),
+        }
+        false
+    }
+}
+
+impl Drop for Debugger {
+    /// Runs that end by running out of input unwind rather than halting
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+fn halts(instruction: &dyn Instruction, vm: &VM) -> bool {
+    match instruction.opcode() {
+        Halt::OPCODE => true,
+        Ret::OPCODE => vm.stack.is_empty(),
+        _ => false,
+    }
+}
+
+fn read_line(prompt: &str) -> Option<String> {
+    print!("{prompt}");
+    stdout().flush().expect("Failed to flush stdout");
+    let mut line = String::new();
+    match stdin().lock().read_line(&mut line) {
+        Ok(0) => None,
+        Ok(_) => Some(line.trim().to_string()),
+        Err(err) => panic!("Failed to read line: {err}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::side_effects::MockSideEffects;
+
+    #[test]
+    fn test_source_script() {
+        let path = std::env::temp_dir().join(format!("debugger-{}.script", std::process::id()));
+        std::fs::write(
+            &path,
+            "# set up, then print and patch at the breakpoint\n\
+             set reg1 7\n\
+             commands 5\n\
+             regs\n\
+             set reg0 42\n\
+             g\n\
+             end\n",
+        )
+        .unwrap();
+        let mut debugger = Debugger::new(Symbols::default());
+        let mut vm = VM::default();
+        debugger.source(&mut vm, path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(vm.registers[1], 7);
+        assert!(debugger.has_breakpoint(5));
+        assert_eq!(vm.registers[0], 0);
+
+        // No commands run away from the breakpoint
+        assert!(!debugger.run_bp_commands(&mut vm));
+        vm.pc = 5;
+        debugger.single_step = true;
+        assert!(debugger.run_bp_commands(&mut vm));
+        assert_eq!(vm.registers[0], 42);
+        assert!(!debugger.single_step);
+    }
+
+    #[test]
+    fn test_memoize_confirmation() {
+        // call 6027 at 0, the teleporter's confirmation, with the patches off
+        let mut vm = VM::challenge();
+        vm.memory[..2].copy_from_slice(&[Call::OPCODE, 6027]);
+        let mut debugger = Debugger::new(Symbols::default());
+        debugger.set_call_cache(CallCache::new(&vm.memory, 6027).unwrap());
+        debugger.swap_confirmation(&vm.memory, 25734);
+        (vm.registers[0], vm.registers[1], vm.registers[7]) = (4, 1, 25734);
+        let mut side_effects = MockSideEffects::default();
+        while vm.pc != 2 {
+            debugger.step(&mut vm, &mut side_effects);
+        }
+        assert_eq!(vm.registers[0], 6);
+        // The real routine ran rather than being swapped out in one step, and
+        // only finished this soon because it was memoized
+        assert!((1000..10_000_000).contains(&debugger.steps));
+    }
+
+    #[test]
+    fn test_script_input() {
+        let path = std::env::temp_dir().join(format!("debugger-{}.replay", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "look\n").unwrap();
+        let mut side_effects = FileBackedEffects::new(path);
+        let mut debugger = Debugger::new(Symbols::default());
+        let mut vm = VM::default();
+        let read = |side_effects: &mut FileBackedEffects, len| {
+            (0..len)
+                .map(|_| side_effects.read() as u8 as char)
+                .collect::<String>()
+        };
+
+        // Input sent by scripts comes first, and leaves the replay alone
+        for _ in 0..2 {
+            debugger.eval_script(&mut vm, r#"send("inv");"#);
+            debugger.queue_input(&mut side_effects);
+        }
+        assert_eq!(read(&mut side_effects, 13), "inv\ninv\nlook\n");
+        assert!(side_effects.exhausted());
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "look\n");
+
+        // Unless it's recorded, where it goes in the replay after the line
+        // being read
+        let mut side_effects = FileBackedEffects::new(path);
+        assert_eq!(read(&mut side_effects, 2), "lo");
+        debugger.execute(&mut vm, "record");
+        debugger.eval_script(&mut vm, r#"send("inv");"#);
+        debugger.queue_input(&mut side_effects);
+        assert!(!side_effects.exhausted());
+        assert_eq!(read(&mut side_effects, 7), "ok\ninv\n");
+        assert!(side_effects.exhausted());
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "look\ninv\n");
+
+        // And it replays from there next time
+        let mut side_effects = FileBackedEffects::new(path);
+        assert_eq!(read(&mut side_effects, 9), "look\ninv\n");
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -0,0 +1,232 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+};
+
+use serde_json::{json, Value};
+
+use itertools::Itertools;
+
+use crate::{game::Game, room::Room};
+
+/// How many moves from where the game first stops to look for the room pointer
+const PROBE_DEPTH: usize = 2;
+
+/// Tells rooms apart by their title and description, and the game's own id
+/// for the room where that's known
+pub(crate) type RoomKey = (String, String, Option<u16>);
+
+pub(crate) fn room_key(game: &Game, room: &Room) -> RoomKey {
+    let pointer = game
+        .room_pointer()
+        .map(|addr| game.vm().memory[addr as usize]);
+    (room.title.clone(), room.description.clone(), pointer)
+}
+
+/// Finds where the game keeps the current room (2732 in our binary), by
+/// walking a few moves from here and looking for the first address whose
+/// value changes exactly when the room's text does, including coming back to
+/// a room. (In ours, 2733 follows the room too, and would do as well.)
+pub(crate) fn find_room_pointer(game: &Game) -> Option<u16> {
+    let here = |game: &mut Game| {
+        let reply = game.send("look");
+        let room = Room::parse(&reply.text).filter(|_| !game.halted())?;
+        Some(room)
+    };
+    let room = here(&mut game.clone())?;
+    let mut snapshots = vec![(room, game.clone())];
+    let mut frontier = 0..1;
+    for _ in 0..PROBE_DEPTH {
+        for n in frontier.clone() {
+            for exit in snapshots[n].0.exits.clone() {
+                let mut next = snapshots[n].1.clone();
+                next.send(&format!("go {exit}"));
+                if let Some(room) = (!next.halted()).then(|| here(&mut next.clone())).flatten() {
+                    snapshots.push((room, next));
+                }
+            }
+        }
+        frontier = frontier.end..snapshots.len();
+    }
+    let text = |n: usize| (&snapshots[n].0.title, &snapshots[n].0.description);
+    let pairs = (0..snapshots.len()).tuple_combinations().collect_vec();
+    // Without a room visited twice, a counter would look like a pointer
+    if pairs.iter().all(|&(a, b)| text(a) != text(b)) {
+        return None;
+    }
+    let memory = |n: usize| &snapshots[n].1.vm().memory;
+    (0..memory(0).len())
+        .filter(|&addr| {
+            (pairs.iter())
+                .all(|&(a, b)| (text(a) == text(b)) == (memory(a)[addr] == memory(b)[addr]))
+        })
+        .map(|addr| addr as u16)
+        .next()
+}
+
+/// A room found while exploring, and how to get there
+pub(crate) struct MapRoom {
+    pub(crate) room: Room,
+    /// The game's own id for the room, if its room pointer was found
+    pub(crate) pointer: Option<u16>,
+    /// The shortest list of commands leading here from the start
+    pub(crate) path: Vec<String>,
+    /// Where each exit leads, or `None` if taking it ends the game
+    pub(crate) exits: Vec<(String, Option<usize>)>,
+}
+
+/// Every room reachable from a starting point by going through exits
+pub(crate) struct WorldMap {
+    pub(crate) rooms: Vec<MapRoom>,
+}
+
+impl MapRoom {
+    pub(crate) fn key(&self) -> RoomKey {
+        let room = &self.room;
+        (room.title.clone(), room.description.clone(), self.pointer)
+    }
+}
+
+impl WorldMap {
+    /// Explores breadth-first from a game waiting for a command, so each room
+    /// is first found by its shortest path. Rooms are told apart by their text
+    /// and the game's room pointer, since some (like the maze) share their
+    /// text; without the pointer, rooms that look alike are taken to be one.
+    pub(crate) fn explore(game: &Game, start: Room) -> Self {
+        let pointer = |game: &Game| {
+            game.room_pointer()
+                .map(|addr| game.vm().memory[addr as usize])
+        };
+        let mut map = Self { rooms: Vec::new() };
+        let mut ids = HashMap::new();
+        let mut queue = VecDeque::new();
+        ids.insert(room_key(game, &start), 0);
+        map.rooms.push(MapRoom {
+            room: start,
+            pointer: pointer(game),
+            path: Vec::new(),
+            exits: Vec::new(),
+        });
+        queue.push_back((0, game.clone()));
+        while let Some((id, game)) = queue.pop_front() {
+            for exit in map.rooms[id].room.exits.clone() {
+                let command = format!("go {exit}");
+                let mut next = game.clone();
+                let reply = next.send(&command);
+                let room = match Room::parse(&reply.text) {
+                    Some(room) if !next.halted() => room,
+                    _ => {
+                        map.rooms[id].exits.push((exit, None));
+                        continue;
+                    }
+                };
+                let next_id = *ids.entry(room_key(&next, &room)).or_insert_with(|| {
+                    let mut path = map.rooms[id].path.clone();
+                    path.push(command);
+                    map.rooms.push(MapRoom {
+                        room,
+                        pointer: pointer(&next),
+                        path,
+                        exits: Vec::new(),
+                    });
+                    queue.push_back((map.rooms.len() - 1, next));
+                    map.rooms.len() - 1
+                });
+                map.rooms[id].exits.push((exit, Some(next_id)));
+            }
+        }
+        map
+    }
+
+    /// How many moves it takes to get from one room to each other, going
+    /// through the exits as they were explored, or `None` if there's no way
+    pub(crate) fn distances(&self, from: usize) -> Vec<Option<usize>> {
+        let mut distances = vec![None; self.rooms.len()];
+        distances[from] = Some(0);
+        let mut queue = VecDeque::from([from]);
+        while let Some(id) = queue.pop_front() {
+            let distance = distances[id].map(|distance| distance + 1);
+            for &(_, target) in &self.rooms[id].exits {
+                if let Some(target) = target.filter(|&target| distances[target].is_none()) {
+                    distances[target] = distance;
+                    queue.push_back(target);
+                }
+            }
+        }
+        distances
+    }
+
+    pub(crate) fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph map {\n");
+        for (id, room) in self.rooms.iter().enumerate() {
+            let label = match room.pointer {
+                Some(pointer) => format!("{} ({pointer})", room.room.title),
+                None => room.room.title.clone(),
+            };
+            writeln!(dot, "    r{id} [label={label:?}];").unwrap();
+        }
+        for (id, room) in self.rooms.iter().enumerate() {
+            for (n, (exit, target)) in room.exits.iter().enumerate() {
+                let target = match target {
+                    Some(target) => format!("r{target}"),
+                    None => {
+                        writeln!(dot, "    r{id}_{n} [label=\"game over\", shape=none];").unwrap();
+                        format!("r{id}_{n}")
+                    }
+                };
+                writeln!(dot, "    r{id} -> {target} [label={exit:?}];").unwrap();
+            }
+        }
+        dot += "}\n";
+        dot
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        let rooms = (self.rooms.iter().enumerate())
+            .map(|(id, room)| {
+                let exits = (room.exits.iter())
+                    .map(|(exit, target)| (exit.clone(), json!(target)))
+                    .collect::<serde_json::Map<_, _>>();
+                json!({
+                    "id": id,
+                    "pointer": room.pointer,
+                    "room": room.room.to_json(),
+                    "path": room.path,
+                    "exits": exits,
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({ "rooms": rooms })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_explore_from_start() {
+        let mut game = Game::new();
+        let start = Room::parse(&game.run().text).unwrap();
+        assert_eq!(game.room_pointer(), Some(2732));
+        let map = WorldMap::explore(&game, start);
+        let find =
+            |title: &'static str| (map.rooms.iter()).filter(move |room| room.room.title == title);
+        assert_eq!(map.rooms[0].room.title, "Foothills");
+        let bridge = find("Rope bridge").next().unwrap();
+        assert_eq!(
+            bridge.path,
+            vec!["go doorway", "go north", "go north", "go bridge"]
+        );
+        // Distances through the exits agree with the shortest paths
+        let distances = map.distances(0);
+        for (room, distance) in map.rooms.iter().zip(distances) {
+            assert_eq!(distance, Some(room.path.len()));
+        }
+        // The maze's rooms all look alike, but have their own pointers
+        assert!(find("Twisty passages").count() > 5);
+        // Wandering in the dark gets you eaten
+        let lost = find("Panicked and lost").next().unwrap();
+        assert!(lost.exits.iter().all(|(_, target)| target.is_none()));
+    }
+}
@@ -0,0 +1,162 @@
+use std::fmt;
+
+use crate::{
+    debugger::Debugger,
+    explore::find_room_pointer,
+    room::{parse_inventory, Room},
+    side_effects::BufferedSideEffects,
+    symbols::Symbols,
+    VM,
+};
+
+/// Plays the game headlessly, one command at a time:
+///
+/// ```ignore
+/// let mut game = Game::new();
+/// game.run().expect("What do you do?");
+/// game.send("take tablet").expect("Taken.");
+/// ```
+pub(crate) struct Game {
+    /// Boxed so snapshots are cheap to move around
+    vm: Box<VM>,
+    debugger: Debugger,
+    side_effects: BufferedSideEffects,
+    /// Where the game keeps the current room, found when it first waits for a command
+    room_pointer: Option<u16>,
+    probed: bool,
+}
+
+/// The text the game printed in response to a command
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Reply {
+    pub(crate) text: String,
+}
+
+impl Reply {
+    pub(crate) fn contains(&self, text: &str) -> bool {
+        self.text.contains(text)
+    }
+
+    /// Panics with the full output if `text` wasn't printed
+    #[track_caller]
+    pub(crate) fn expect(self, text: &str) -> Self {
+        if !self.contains(text) {
+            panic!("Expected {text:?} in game output:\n{}", self.text);
+        }
+        self
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// Snapshots the game, with a debugger that plays it the same way
+impl Clone for Game {
+    fn clone(&self) -> Self {
+        Self {
+            vm: self.vm.clone(),
+            debugger: self.debugger.snapshot(),
+            side_effects: self.side_effects.clone(),
+            room_pointer: self.room_pointer,
+            probed: self.probed,
+        }
+    }
+}
+
+impl Game {
+    #[cfg(test)]
+    pub(crate) fn new() -> Self {
+        Self::from_vm(VM::challenge())
+    }
+
+    pub(crate) fn from_vm(vm: VM) -> Self {
+        Self {
+            vm: Box::new(vm),
+            debugger: Debugger::new(Symbols::default()),
+            side_effects: BufferedSideEffects::default(),
+            room_pointer: None,
+            probed: false,
+        }
+    }
+
+    /// Puts this in the eighth register and swaps calls to the teleporter's
+    /// confirmation for the routine lifted from the binary, rather than
+    /// patching around it
+    pub(crate) fn swap_confirmation(&mut self, magic: u16) {
+        self.debugger.swap_confirmation(&self.vm.memory, magic);
+    }
+
+    pub(crate) fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    /// The address holding the current room, if the game's been found to have one
+    pub(crate) fn room_pointer(&self) -> Option<u16> {
+        self.room_pointer
+    }
+
+    pub(crate) fn halted(&self) -> bool {
+        self.side_effects.halted
+    }
+
+    /// Runs until the game waits for a command or halts, returning what it printed.
+    /// The first time, it also looks for the room pointer from where it stops.
+    pub(crate) fn run(&mut self) -> Reply {
+        while !self.side_effects.halted {
+            if self.side_effects.input.is_empty() && self.debugger.reads_input(&self.vm) {
+                break;
+            }
+            self.debugger.step(&mut self.vm, &mut self.side_effects);
+        }
+        if !self.probed {
+            // Set first, so the snapshots taken while probing don't probe too
+            self.probed = true;
+            self.room_pointer = find_room_pointer(self);
+        }
+        Reply {
+            text: std::mem::take(&mut self.side_effects.output),
+        }
+    }
+
+    /// What the player is carrying, checked on a copy of the game
+    pub(crate) fn inventory(&self) -> Vec<String> {
+        let reply = self.clone().send("inv");
+        parse_inventory(&reply.text).expect("Failed to read the inventory")
+    }
+
+    /// The room the player is in, checked on a copy of the game
+    pub(crate) fn look(&self) -> Room {
+        let reply = self.clone().send("look");
+        Room::parse(&reply.text).expect("Failed to find a room")
+    }
+
+    /// Enters a command and runs until the game wants the next one
+    pub(crate) fn send(&mut self, command: &str) -> Reply {
+        if self.halted() {
+            panic!("Can't send {command:?}, the game has halted");
+        }
+        self.side_effects.send(command);
+        self.side_effects.send("\n");
+        self.run()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_send_and_expect() {
+        let mut game = Game::new();
+        game.run()
+            .expect("== Foothills ==")
+            .expect("What do you do?");
+        game.send("take tablet").expect("Taken.");
+        let reply = game.send("look");
+        assert!(!reply.contains("- tablet"));
+        game.send("inv").expect("- tablet");
+    }
+}
@@ -0,0 +1,547 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use itertools::Itertools;
+
+use crate::{debugger::Debugger, side_effects::SideEffects, VM};
+
+/// Describes the registers reported in the `g` packet, in order. GDB addresses
+/// bytes while the VM addresses 16-bit words, so word `n` of memory is exposed
+/// at bytes `2n` (low) and `2n + 1` (high), and pc is reported as a byte address
+/// to match. sp is the depth of the VM stack, which is not mapped into memory.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.synacor.vm">
+    <reg name="r0" bitsize="16" type="uint16" regnum="0"/>
+    <reg name="r1" bitsize="16" type="uint16"/>
+    <reg name="r2" bitsize="16" type="uint16"/>
+    <reg name="r3" bitsize="16" type="uint16"/>
+    <reg name="r4" bitsize="16" type="uint16"/>
+    <reg name="r5" bitsize="16" type="uint16"/>
+    <reg name="r6" bitsize="16" type="uint16"/>
+    <reg name="r7" bitsize="16" type="uint16"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="16" type="uint16"/>
+  </feature>
+</target>
+"#;
+
+const PC_REG: usize = 8;
+const SP_REG: usize = 9;
+const NUM_REGS: usize = 10;
+const MEMORY_BYTES: usize = 2 * 32768;
+
+/// How often (in instructions) a running VM checks for an interrupt from GDB
+const INTERRUPT_POLL_INTERVAL: usize = 4096;
+
+enum StopReason {
+    Step,
+    Breakpoint,
+    Interrupted,
+    Halted,
+}
+
+/// A stub speaking the GDB remote serial protocol, so debugger frontends can
+/// drive the VM. Game output and input stay on the server's terminal.
+pub(crate) struct GdbServer<'a> {
+    debugger: &'a mut Debugger,
+    vm: &'a mut VM,
+    side_effects: &'a mut dyn SideEffects,
+    stream: TcpStream,
+    no_ack: bool,
+    halted: bool,
+}
+
+impl<'a> GdbServer<'a> {
+    pub(crate) fn serve(
+        address: &str,
+        debugger: &'a mut Debugger,
+        vm: &'a mut VM,
+        side_effects: &'a mut dyn SideEffects,
+    ) {
+        let Ok(listener) = TcpListener::bind(address) else {
+            panic!("Failed to listen on {address}");
+        };
+        println!("Waiting for GDB to connect to {address}");
+        let (stream, peer) = listener.accept().expect("Failed to accept connection");
+        println!("GDB connected from {peer}");
+        let mut server = GdbServer {
+            debugger,
+            vm,
+            side_effects,
+            stream,
+            no_ack: false,
+            halted: false,
+        };
+        server.run();
+    }
+
+    fn run(&mut self) {
+        while let Some(packet) = self.read_packet() {
+            let Some(response) = self.handle(&packet) else {
+                break;
+            };
+            self.write_packet(&response);
+            // The reply to this one is still acknowledged
+            if packet == "QStartNoAckMode" {
+                self.no_ack = true;
+            }
+            if self.halted {
+                break;
+            }
+        }
+        println!("GDB disconnected");
+    }
+
+    /// Returns the response to the packet, or None if the session should end
+    fn handle(&mut self, packet: &str) -> Option<String> {
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let response = match command {
+            "?" => "S05".into(),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "Z" => self.set_breakpoint(args, true),
+            "z" => self.set_breakpoint(args, false),
+            "c" => self.resume(false),
+            "s" => self.resume(true),
+            "H" => "OK".into(),
+            "T" => "OK".into(),
+            "k" => return None,
+            "D" => {
+                self.write_packet("OK");
+                return None;
+            }
+            "v" => self.handle_v(args),
+            "q" | "Q" => self.handle_query(packet),
+            _ => String::new(),
+        };
+        Some(response)
+    }
+
+    fn handle_v(&mut self, args: &str) -> String {
+        if args == "Cont?" {
+            return "vCont;c;C;s;S".into();
+        }
+        let Some(actions) = args.strip_prefix("Cont;") else {
+            return String::new();
+        };
+        // Only one thread, so the first action applies
+        match actions.chars().next() {
+            Some('c' | 'C') => self.resume(false),
+            Some('s' | 'S') => self.resume(true),
+            _ => "E01".into(),
+        }
+    }
+
+    fn handle_query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;swbreak+".into();
+        }
+        if packet == "QStartNoAckMode" {
+            return "OK".into();
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, length)) = range
+                .split(',')
+                .map(|n| usize::from_str_radix(n, 16))
+                .collect_tuple()
+            else {
+                return "E01".into();
+            };
+            let (Ok(offset), Ok(length)) = (offset, length) else {
+                return "E01".into();
+            };
+            let chunk = TARGET_XML.get(offset.min(TARGET_XML.len())..).unwrap_or("");
+            return if chunk.len() > length {
+                format!("m{}", &chunk[..length])
+            } else {
+                format!("l{chunk}")
+            };
+        }
+        match packet {
+            "qAttached" => "1".into(),
+            "qC" => "QC1".into(),
+            "qfThreadInfo" => "m1".into(),
+            "qsThreadInfo" => "l".into(),
+            _ => String::new(),
+        }
+    }
+
+    fn register(&self, reg: usize) -> u16 {
+        match reg {
+            0..=7 => self.vm.registers[reg],
+            PC_REG => self.vm.pc * 2,
+            SP_REG => self.vm.stack.len() as u16,
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_register(&mut self, reg: usize, value: u16) {
+        match reg {
+            0..=7 => self.vm.registers[reg] = value % 32768,
+            PC_REG => self.vm.pc = value / 2,
+            SP_REG => self.vm.stack.resize(value as usize, 0),
+            _ => unreachable!(),
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        (0..NUM_REGS)
+            .map(|reg| encode_hex(&self.register(reg).to_le_bytes()))
+            .collect()
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        let Some(bytes) = decode_hex(args) else {
+            return "E01".into();
+        };
+        if bytes.len() != NUM_REGS * 2 {
+            return "E01".into();
+        }
+        for (reg, value) in bytes.chunks(2).enumerate() {
+            self.set_register(reg, u16::from_le_bytes([value[0], value[1]]));
+        }
+        "OK".into()
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        match usize::from_str_radix(args, 16) {
+            Ok(reg) if reg < NUM_REGS => encode_hex(&self.register(reg).to_le_bytes()),
+            _ => "E01".into(),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let Some((reg, value)) = args.split_once('=') else {
+            return "E01".into();
+        };
+        let (Ok(reg), Some(value)) = (usize::from_str_radix(reg, 16), decode_hex(value)) else {
+            return "E01".into();
+        };
+        if reg >= NUM_REGS || value.len() != 2 {
+            return "E01".into();
+        }
+        self.set_register(reg, u16::from_le_bytes([value[0], value[1]]));
+        "OK".into()
+    }
+
+    fn parse_range(args: &str) -> Option<(usize, usize)> {
+        let (addr, len) = args.split_once(',')?;
+        let addr = usize::from_str_radix(addr, 16).ok()?;
+        let len = usize::from_str_radix(len, 16).ok()?;
+        if addr + len > MEMORY_BYTES {
+            return None;
+        }
+        Some((addr, len))
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let Some((addr, len)) = Self::parse_range(args) else {
+            return "E01".into();
+        };
+        let bytes = (addr..addr + len)
+            .map(|byte| self.vm.memory[byte / 2].to_le_bytes()[byte % 2])
+            .collect_vec();
+        encode_hex(&bytes)
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let Some((range, data)) = args.split_once(':') else {
+            return "E01".into();
+        };
+        let (Some((addr, len)), Some(data)) = (Self::parse_range(range), decode_hex(data)) else {
+            return "E01".into();
+        };
+        if data.len() != len {
+            return "E01".into();
+        }
+        for (byte, value) in (addr..addr + len).zip(data) {
+            let mut word = self.vm.memory[byte / 2].to_le_bytes();
+            word[byte % 2] = value;
+            self.vm.memory[byte / 2] = u16::from_le_bytes(word);
+        }
+        "OK".into()
+    }
+
+    fn set_breakpoint(&mut self, args: &str, insert: bool) -> String {
+        let mut fields = args.split(',');
+        // Only software breakpoints are supported
+        if fields.next() != Some("0") {
+            return String::new();
+        }
+        let Some(Ok(addr)) = fields.next().map(|addr| usize::from_str_radix(addr, 16)) else {
+            return "E01".into();
+        };
+        if addr >= MEMORY_BYTES {
+            return "E01".into();
+        }
+        let addr = (addr / 2) as u16;
+        if insert {
+            self.debugger.add_breakpoint(addr);
+        } else {
+            self.debugger.remove_breakpoint(addr);
+        }
+        "OK".into()
+    }
+
+    fn resume(&mut self, single_step: bool) -> String {
+        let reason = self.execute(single_step);
+        match reason {
+            StopReason::Step | StopReason::Interrupted => "S05".into(),
+            StopReason::Breakpoint => "T05swbreak:;".into(),
+            StopReason::Halted => {
+                self.halted = true;
+                "W00".into()
+            }
+        }
+    }
+
+    fn execute(&mut self, single_step: bool) -> StopReason {
+        for count in 0.. {
+            if self.debugger.halts(self.vm) {
+                return StopReason::Halted;
+            }
+            // A breakpoint at the current pc is stepped over when resuming
+            if count > 0 && self.debugger.has_breakpoint(self.vm.pc) {
+                return StopReason::Breakpoint;
+            }
+            if single_step && count == 1 {
+                return StopReason::Step;
+            }
+            if count % INTERRUPT_POLL_INTERVAL == INTERRUPT_POLL_INTERVAL - 1 && self.interrupted()
+            {
+                return StopReason::Interrupted;
+            }
+            self.debugger.step(self.vm, self.side_effects);
+        }
+        unreachable!()
+    }
+
+    /// Checks (without blocking) whether GDB sent an interrupt (Ctrl-C)
+    fn interrupted(&mut self) -> bool {
+        self.stream
+            .set_nonblocking(true)
+            .expect("Failed to configure socket");
+        let mut buf = [0; 1];
+        let result = self.stream.peek(&mut buf);
+        self.stream
+            .set_nonblocking(false)
+            .expect("Failed to configure socket");
+        match result {
+            Ok(1) if buf[0] == 0x03 => {
+                self.stream.read_exact(&mut buf).expect("Failed to read");
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut buf = [0; 1];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(1) => return Some(buf[0]),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                _ => return None,
+            }
+        }
+    }
+
+    /// Reads the next `$packet#checksum`, acknowledging it unless in no-ack mode
+    fn read_packet(&mut self) -> Option<String> {
+        loop {
+            match self.read_byte()? {
+                b'$' => (),
+                // An interrupt while stopped is answered with a stop reply
+                0x03 => return Some("?".into()),
+                _ => continue,
+            }
+            // The checksum covers the bytes as sent, before unescaping
+            let mut raw = Vec::new();
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    b'}' => {
+                        let byte = self.read_byte()?;
+                        raw.extend([b'}', byte]);
+                        data.push(byte ^ 0x20);
+                    }
+                    byte => {
+                        raw.push(byte);
+                        data.push(byte);
+                    }
+                }
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let valid = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok())
+                == Some(checksum_of(&raw));
+            if !self.no_ack {
+                let ack: &[u8] = if valid { b"+" } else { b"-" };
+                self.stream.write_all(ack).ok()?;
+            }
+            if valid {
+                return String::from_utf8(data).ok();
+            }
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) {
+        let mut escaped = Vec::new();
+        for &byte in data.as_bytes() {
+            if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+                escaped.extend([b'}', byte ^ 0x20]);
+            } else {
+                escaped.push(byte);
+            }
+        }
+        let mut packet = vec![b'$'];
+        packet.extend(&escaped);
+        packet.extend(format!("#{:02x}", checksum_of(&escaped)).bytes());
+        // A dropped connection shows up on the next read
+        let _ = self.stream.write_all(&packet);
+        if !self.no_ack {
+            // Retransmit until acknowledged
+            while let Some(byte) = self.read_byte() {
+                match byte {
+                    b'+' => break,
+                    b'-' => {
+                        let _ = self.stream.write_all(&packet);
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        instructions::{Halt, Noop},
+        side_effects::MockSideEffects,
+        symbols::Symbols,
+    };
+
+    /// A server talking to a client over a local socket
+    fn connect<'a>(
+        debugger: &'a mut Debugger,
+        vm: &'a mut VM,
+        side_effects: &'a mut MockSideEffects,
+    ) -> (GdbServer<'a>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let server = GdbServer {
+            debugger,
+            vm,
+            side_effects,
+            stream,
+            no_ack: false,
+            halted: false,
+        };
+        (server, client)
+    }
+
+    fn read_exactly(client: &mut TcpStream, len: usize) -> String {
+        let mut buf = vec![0; len];
+        client.read_exact(&mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn test_packets() {
+        let (mut debugger, mut vm) = (Debugger::new(Symbols::default()), VM::default());
+        let mut side_effects = MockSideEffects::default();
+        let (mut server, mut client) = connect(&mut debugger, &mut vm, &mut side_effects);
+
+        // A bad checksum is refused, and the resent packet acknowledged
+        client.write_all(b"$g#00$g#67").unwrap();
+        assert_eq!(server.read_packet().as_deref(), Some("g"));
+        assert_eq!(read_exactly(&mut client, 2), "-+");
+        // The checksum covers the escaped bytes
+        client.write_all(b"$a}]b#9d").unwrap();
+        assert_eq!(server.read_packet().as_deref(), Some("a}b"));
+        assert_eq!(read_exactly(&mut client, 1), "+");
+        // Ctrl-C while stopped asks for the stop reason
+        client.write_all(&[0x03]).unwrap();
+        assert_eq!(server.read_packet().as_deref(), Some("?"));
+
+        // Replies wait for an acknowledgement, retransmitting on a refusal
+        client.write_all(b"-+").unwrap();
+        server.write_packet("OK");
+        assert_eq!(read_exactly(&mut client, 12), "$OK#9a$OK#9a");
+        server.no_ack = true;
+        server.write_packet("a#b");
+        assert_eq!(read_exactly(&mut client, 8), "$a}\x03b#43");
+    }
+
+    #[test]
+    fn test_registers_memory_and_breakpoints() {
+        let (mut debugger, mut vm) = (Debugger::new(Symbols::default()), VM::default());
+        (vm.registers[0], vm.registers[7], vm.pc) = (1, 0x1234, 0x10);
+        vm.stack = vec![5, 6];
+        vm.memory[..40].fill(Noop::OPCODE);
+        vm.memory[40] = Halt::OPCODE;
+        vm.memory[101..103].copy_from_slice(&[0x1234, 0xabcd]);
+        let mut side_effects = MockSideEffects::default();
+        let (mut server, _client) = connect(&mut debugger, &mut vm, &mut side_effects);
+
+        // r0-r7, then pc as a byte address and sp as the stack depth, little-endian
+        let registers = format!("0100{}3412{}", "0000".repeat(6), "20000200");
+        assert_eq!(server.handle("g").unwrap(), registers);
+        assert_eq!(server.handle("p8").unwrap(), "2000");
+        assert_eq!(server.handle("P8=1000").unwrap(), "OK");
+        assert_eq!(server.vm.pc, 8);
+
+        // Bytes 0xcb and 0xcc are the high byte of word 101 and the low byte of word 102
+        assert_eq!(server.handle("mcb,2").unwrap(), "12cd");
+        assert_eq!(server.handle("Mcb,2:ffee").unwrap(), "OK");
+        assert_eq!(server.vm.memory[101..103], [0xff34, 0xabee]);
+        assert_eq!(server.handle("mca,4").unwrap(), "34ffeeab");
+        assert_eq!(server.handle("mfffe,4").unwrap(), "E01");
+
+        // Word 12 is byte 24 (0x18); memory is all noops up to the halt at 40
+        server.vm.pc = 0;
+        assert_eq!(server.handle("Z0,18,1").unwrap(), "OK");
+        assert!(server.debugger.has_breakpoint(12));
+        assert_eq!(server.handle("c").unwrap(), "T05swbreak:;");
+        assert_eq!(server.vm.pc, 12);
+        assert_eq!(server.handle("s").unwrap(), "S05");
+        assert_eq!(server.vm.pc, 13);
+        assert_eq!(server.handle("z0,18,1").unwrap(), "OK");
+        assert!(!server.debugger.has_breakpoint(12));
+        server.vm.pc = 0;
+        assert_eq!(server.handle("c").unwrap(), "W00");
+        assert!(server.debugger.halts(server.vm));
+        assert_eq!(server.vm.pc, 40);
+    }
+}
@@ -44,10 +44,27 @@ macro_rules! make_parser {
             $(pub(crate) $args: Operand,)*
         }
         impl $op {
+            #[allow(dead_code)]
+            pub(crate) const OPCODE: u16 = $code;
+
+            #[allow(clippy::new_ret_no_self)]
             pub(crate) fn new($($args: Operand,)*) -> Box<dyn Instruction> {
                 Box::new($op { $($args: $args.into(),)* })
             }
         }
+        impl InstructionInfo for $op {
+            fn opcode(&self) -> u16 {
+                $code
+            }
+
+            fn name(&self) -> &'static str {
+                stringify!($op)
+            }
+
+            fn operands(&self) -> Vec<Operand> {
+                vec![$(self.$args,)*]
+            }
+        }
         impl Display for $op {
             fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
                 write!(f, stringify!($op))?;
@@ -115,10 +132,57 @@ make_parser![parse,
     Noop: 21,
 ];
 
-pub(crate) trait Instruction: InstructionClone + Debug + Display {
+pub(crate) trait Instruction:
+    InstructionClone + InstructionInfo + Debug + Display + Send + Sync
+{
     fn execute(&self, vm: &mut VM, side_effects: &mut dyn SideEffects);
 }
 
+pub(crate) trait InstructionInfo {
+    fn opcode(&self) -> u16;
+    fn name(&self) -> &'static str;
+    fn operands(&self) -> Vec<Operand>;
+
+    /// The register this instruction writes its result to, if any
+    fn register_written(&self) -> Option<usize> {
+        match self.opcode() {
+            Set::OPCODE
+            | Pop::OPCODE
+            | Eq::OPCODE
+            | Gt::OPCODE
+            | Add::OPCODE
+            | Mult::OPCODE
+            | Mod::OPCODE
+            | And::OPCODE
+            | Or::OPCODE
+            | Not::OPCODE
+            | Rmem::OPCODE
+            | In::OPCODE => match self.operands()[0] {
+                Operand::Reg(reg) => Some(reg),
+                Operand::Literal(_) => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Literal operands which refer to a memory address (jump/call targets and rmem/wmem)
+    fn address_operands(&self) -> Vec<u16> {
+        let operands = self.operands();
+        let addresses = match self.opcode() {
+            Jmp::OPCODE | Call::OPCODE | Wmem::OPCODE => &operands[..1],
+            Jt::OPCODE | Jf::OPCODE | Rmem::OPCODE => &operands[1..],
+            _ => &[],
+        };
+        addresses
+            .iter()
+            .filter_map(|operand| match operand {
+                Operand::Literal(value) => Some(*value),
+                Operand::Reg(_) => None,
+            })
+            .collect()
+    }
+}
+
 impl Instruction for Halt {
     fn execute(&self, _vm: &mut VM, side_effects: &mut dyn SideEffects) {
         side_effects.halt();
@@ -298,7 +362,7 @@ pub(crate) enum Operand {
 }
 
 impl Operand {
-    fn value(self, vm: &VM) -> u16 {
+    pub(crate) fn value(self, vm: &VM) -> u16 {
         match self {
             Operand::Literal(value) => value,
             Operand::Reg(reg) => vm.registers[reg],
@@ -336,7 +400,7 @@ impl Display for Operand {
             if c == '\n' {
                 return format!("{literal} '\\n'");
             }
-            return literal;
+            literal
         };
         match self {
             Operand::Literal(value) => write!(f, "[{}]", format_value(*value)),
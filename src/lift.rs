@@ -0,0 +1,274 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::{
+    instructions::{parse, Call, Halt, In, Instruction, Jf, Jmp, Jt, Operand, Out, Pop, Ret, Wmem},
+    side_effects::BufferedSideEffects,
+    VM,
+};
+
+/// A routine lifted out of the binary so it can be run as a function of its
+/// registers. Only routines that leave memory alone and keep to their own
+/// stack can be lifted, so each call's result depends only on the registers
+/// it reads, and calls to itself are memoized on those.
+pub(crate) struct Routine {
+    pub(crate) entry: u16,
+    /// Registers read somewhere in the routine
+    pub(crate) inputs: Vec<usize>,
+    /// Registers written somewhere in the routine
+    pub(crate) outputs: Vec<usize>,
+    /// Whether the routine calls itself
+    pub(crate) recursive: bool,
+    code: HashMap<u16, (Box<dyn Instruction>, u16)>,
+    vm: Box<VM>,
+    memo: HashMap<Vec<u16>, Vec<u16>>,
+}
+
+/// Copies start with an empty memo, which is only kept while memory stays the same
+impl Clone for Routine {
+    fn clone(&self) -> Self {
+        Self {
+            entry: self.entry,
+            inputs: self.inputs.clone(),
+            outputs: self.outputs.clone(),
+            recursive: self.recursive,
+            code: self.code.clone(),
+            vm: self.vm.clone(),
+            memo: HashMap::new(),
+        }
+    }
+}
+
+/// A call in progress
+struct Frame {
+    key: Vec<u16>,
+    return_pc: u16,
+    /// The stack's depth on entry, which it must not pop below
+    depth: usize,
+}
+
+impl Routine {
+    /// Follows every jump from `entry` to collect the routine's code
+    pub(crate) fn lift(memory: &[u16], entry: u16) -> Result<Self, String> {
+        let mut code = HashMap::new();
+        let mut inputs = BTreeSet::new();
+        let mut outputs = BTreeSet::new();
+        let mut recursive = false;
+        let mut pending = vec![entry];
+        while let Some(pc) = pending.pop() {
+            if code.contains_key(&pc) {
+                continue;
+            }
+            let Some((instruction, size)) = parse(memory, pc) else {
+                return Err(format!("{pc}: not an instruction"));
+            };
+            note_registers(instruction.as_ref(), &mut inputs, &mut outputs);
+            let targets = instruction.address_operands();
+            match instruction.opcode() {
+                Wmem::OPCODE | In::OPCODE | Out::OPCODE => {
+                    return Err(format!("{pc}: {instruction} has side effects"));
+                }
+                Jmp::OPCODE | Jt::OPCODE | Jf::OPCODE | Call::OPCODE if targets.is_empty() => {
+                    return Err(format!("{pc}: {instruction} goes to a computed address"));
+                }
+                Call::OPCODE if targets != [entry] => {
+                    return Err(format!("{pc}: {instruction} calls another routine"));
+                }
+                Call::OPCODE => recursive = true,
+                Jmp::OPCODE | Jt::OPCODE | Jf::OPCODE => pending.push(targets[0]),
+                _ => {}
+            }
+            if !matches!(
+                instruction.opcode(),
+                Jmp::OPCODE | Ret::OPCODE | Halt::OPCODE
+            ) {
+                pending.push(pc + size);
+            }
+            code.insert(pc, (instruction, size));
+        }
+        let mut vm = Box::<VM>::default();
+        vm.memory.copy_from_slice(memory);
+        Ok(Self {
+            entry,
+            inputs: inputs.into_iter().collect(),
+            outputs: outputs.into_iter().collect(),
+            recursive,
+            code,
+            vm,
+            memo: HashMap::new(),
+        })
+    }
+
+    /// Finds the teleporter's confirmation: a routine that calls itself and
+    /// reads the eighth register, which nothing else in the game does
+    pub(crate) fn find_confirmation(memory: &[u16]) -> Option<Self> {
+        let mut targets = BTreeSet::new();
+        let mut pos = 0;
+        while pos < memory.len() as u16 {
+            let Some((instruction, size)) = parse(memory, pos) else {
+                pos += 1;
+                continue;
+            };
+            if instruction.opcode() == Call::OPCODE {
+                targets.extend(instruction.address_operands());
+            }
+            pos += size;
+        }
+        (targets.into_iter())
+            .filter_map(|entry| Self::lift(memory, entry).ok())
+            .find(|routine| routine.recursive && routine.inputs.contains(&7))
+    }
+
+    /// Runs the routine from these registers, returning them as it leaves them
+    pub(crate) fn call(&mut self, registers: [u16; 8]) -> Result<[u16; 8], String> {
+        let mut side_effects = BufferedSideEffects::default();
+        let vm = &mut self.vm;
+        vm.registers = registers;
+        vm.stack.clear();
+        let key = |vm: &VM| self.inputs.iter().map(|&reg| vm.registers[reg]).collect();
+        let mut frames = vec![Frame {
+            key: key(vm),
+            return_pc: self.entry,
+            depth: 0,
+        }];
+        let mut pc = self.entry;
+        loop {
+            let (instruction, size) = &self.code[&pc];
+            match instruction.opcode() {
+                Call::OPCODE => {
+                    let key = key(vm);
+                    if let Some(values) = self.memo.get(&key) {
+                        for (&reg, &value) in self.outputs.iter().zip(values) {
+                            vm.registers[reg] = value;
+                        }
+                        pc += size;
+                        continue;
+                    }
+                    frames.push(Frame {
+                        key,
+                        return_pc: pc + size,
+                        depth: vm.stack.len(),
+                    });
+                    pc = self.entry;
+                }
+                Ret::OPCODE => {
+                    let frame = frames.pop().unwrap();
+                    if vm.stack.len() != frame.depth {
+                        return Err(format!("{pc}: returns without popping what it pushed"));
+                    }
+                    let values = self.outputs.iter().map(|&reg| vm.registers[reg]);
+                    self.memo.insert(frame.key, values.collect());
+                    if frames.is_empty() {
+                        return Ok(vm.registers);
+                    }
+                    pc = frame.return_pc;
+                }
+                Halt::OPCODE => return Err(format!("{pc}: halts")),
+                Pop::OPCODE if vm.stack.len() == frames.last().unwrap().depth => {
+                    return Err(format!("{pc}: pops its caller's stack"));
+                }
+                _ => {
+                    vm.pc = pc + size;
+                    instruction.execute(vm, &mut side_effects);
+                    pc = vm.pc;
+                }
+            }
+        }
+    }
+
+    /// Runs a call to the routine in place of the VM, leaving its registers
+    /// as the call would
+    pub(crate) fn run(&mut self, vm: &mut VM) -> Result<(), String> {
+        if self.vm.memory != vm.memory {
+            self.vm.memory.copy_from_slice(&vm.memory);
+            self.memo.clear();
+        }
+        vm.registers = self.call(vm.registers)?;
+        Ok(())
+    }
+
+    /// The confirmation's result for `f(a, b)`, taking `a` and `b` in the
+    /// first two registers and the magic number in the eighth
+    pub(crate) fn confirm(&mut self, magic: u16, a: u16, b: u16) -> Result<u16, String> {
+        let mut registers = [0; 8];
+        (registers[0], registers[1], registers[7]) = (a, b, magic);
+        Ok(self.call(registers)?[0])
+    }
+}
+
+/// The registers read and written by a routine and everything it calls, which
+/// may be more than any one call uses
+pub(crate) fn footprint(memory: &[u16], entry: u16) -> Result<(Vec<usize>, Vec<usize>), String> {
+    let mut seen = BTreeSet::new();
+    let mut inputs = BTreeSet::new();
+    let mut outputs = BTreeSet::new();
+    let mut pending = vec![entry];
+    while let Some(pc) = pending.pop() {
+        if !seen.insert(pc) {
+            continue;
+        }
+        let Some((instruction, size)) = parse(memory, pc) else {
+            return Err(format!("{pc}: not an instruction"));
+        };
+        note_registers(instruction.as_ref(), &mut inputs, &mut outputs);
+        let targets = instruction.address_operands();
+        match instruction.opcode() {
+            Jmp::OPCODE | Jt::OPCODE | Jf::OPCODE | Call::OPCODE if targets.is_empty() => {
+                return Err(format!("{pc}: {instruction} goes to a computed address"));
+            }
+            Jmp::OPCODE | Jt::OPCODE | Jf::OPCODE | Call::OPCODE => pending.push(targets[0]),
+            _ => {}
+        }
+        if !matches!(
+            instruction.opcode(),
+            Jmp::OPCODE | Ret::OPCODE | Halt::OPCODE
+        ) {
+            pending.push(pc + size);
+        }
+    }
+    Ok((inputs.into_iter().collect(), outputs.into_iter().collect()))
+}
+
+fn note_registers(
+    instruction: &dyn Instruction,
+    inputs: &mut BTreeSet<usize>,
+    outputs: &mut BTreeSet<usize>,
+) {
+    let written = instruction.register_written();
+    for (n, operand) in instruction.operands().into_iter().enumerate() {
+        match operand {
+            Operand::Reg(reg) if n == 0 && written.is_some() => outputs.insert(reg),
+            Operand::Reg(reg) => inputs.insert(reg),
+            Operand::Literal(_) => false,
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::teleporter::Teleporter;
+
+    #[test]
+    fn test_lift_confirmation() {
+        let vm = VM::challenge();
+        let mut routine = Routine::find_confirmation(&vm.memory).unwrap();
+        assert_eq!(routine.entry, 6027);
+        assert_eq!(
+            (routine.inputs.clone(), routine.outputs.clone()),
+            (vec![0, 1, 7], vec![0, 1])
+        );
+        for magic in [1, 2, 3, 25734] {
+            for a in 0..=3 {
+                for b in 0..5 {
+                    let lifted = routine.confirm(magic, a, b).unwrap();
+                    assert_eq!(
+                        lifted,
+                        Teleporter::new(magic).check(a, b),
+                        "f({a}, {b}) with {magic}"
+                    );
+                }
+            }
+        }
+        assert!(Routine::lift(&vm.memory, 5489).is_err());
+    }
+}
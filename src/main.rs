@@ -1,34 +1,290 @@
+mod call_cache;
+mod codes;
+mod coins;
+mod coverage;
+mod dap_server;
 mod debugger;
+mod explore;
+mod game;
+mod gdb_server;
 mod instructions;
+mod lift;
 mod orb_maze;
+mod planner;
+mod profiler;
+mod room;
+mod scripting;
 mod side_effects;
+mod solver;
+mod symbols;
 mod teleporter;
+mod trace;
+mod trace_diff;
 
+use std::{collections::BTreeSet, io::Write};
+
+use call_cache::CallCache;
 use clap::Parser;
+use codes::CodeExtractor;
+use coins::CoinPuzzle;
+use coverage::Coverage;
+use dap_server::DapServer;
 use debugger::Debugger;
-use instructions::parse;
+use explore::WorldMap;
+use game::{Game, Reply};
+use gdb_server::GdbServer;
+use instructions::{parse, Call};
 use itertools::Itertools;
-use orb_maze::Maze;
+use lift::Routine;
+use orb_maze::{Arithmetic, Maze, Weights};
+use planner::Plan;
+use profiler::Profiler;
+use room::Room;
 use side_effects::{BasicSideEffects, FileBackedEffects, SideEffects};
+use solver::Solver;
+use symbols::{SymbolKind, Symbols};
 use teleporter::Teleporter;
+use trace::{TraceFormat, TraceSink};
+
+/// Options for recording what the VM does while it runs
+#[derive(clap::Args, Clone, Debug, Default)]
+struct Instrumentation {
+    /// Write a record of every executed instruction to this file
+    #[arg(long)]
+    trace_file: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = TraceFormat::Binary)]
+    trace_format: TraceFormat,
+
+    /// Only record instructions in this address range, e.g. 5400..6100 (may be repeated)
+    #[arg(long, value_parser = trace::parse_range)]
+    trace_range: Vec<std::ops::Range<u16>>,
+
+    /// Count executions per address and function, writing folded stacks (for
+    /// flamegraph tools) to this file and a report to stderr
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Merge the addresses executed into this coverage file
+    #[arg(long)]
+    coverage: Option<String>,
+
+    /// List the challenge codes printed (and any in ./arch-spec) when the run ends
+    #[arg(long)]
+    codes: bool,
+
+    /// Memoize calls to the routine at this address while they stay pure
+    #[arg(long)]
+    memoize: Option<u16>,
+
+    /// Set the eighth register to this once the self-test passes, and swap calls to the
+    /// teleporter's confirmation for the routine lifted from the binary instead of patching
+    /// around them
+    #[arg(long)]
+    eighth_register: Option<u16>,
+}
 
-#[derive(clap::ValueEnum, Copy, Clone, Debug)]
+impl Instrumentation {
+    fn attach(self, debugger: &mut Debugger, vm: &VM) {
+        if let Some(path) = &self.trace_file {
+            let sink = TraceSink::create(path, self.trace_format, self.trace_range);
+            debugger.set_trace_sink(sink);
+        }
+        if let Some(path) = &self.profile {
+            debugger.set_profiler(Profiler::new(path));
+        }
+        if let Some(path) = &self.coverage {
+            debugger.set_coverage_file(path);
+        }
+        if self.codes {
+            let mut codes = CodeExtractor::default();
+            if let Ok(spec) = std::fs::read_to_string("arch-spec") {
+                codes.scan(&spec, "arch-spec");
+            }
+            debugger.set_code_extractor(codes);
+        }
+        if let Some(entry) = self.memoize {
+            let cache = CallCache::new(&vm.memory, entry)
+                .unwrap_or_else(|err| panic!("Failed to memoize calls to {entry}: {err}"));
+            debugger.set_call_cache(cache);
+        }
+        if let Some(magic) = self.eighth_register {
+            debugger.swap_confirmation(&vm.memory, magic);
+        }
+    }
+}
+
+#[derive(clap::Subcommand, Clone, Debug)]
 enum Command {
-    Run,
-    Debug,
-    DumpBinary,
-    CalculateTeleporterNumber,
-    SolveMaze,
+    Run {
+        #[command(flatten)]
+        instrumentation: Instrumentation,
+    },
+    Debug {
+        /// File of shell commands to run at startup
+        #[arg(long)]
+        script: Option<String>,
+
+        #[command(flatten)]
+        instrumentation: Instrumentation,
+    },
+    DumpBinary {
+        /// Mark each instruction as executed (+) or never executed (-) in this coverage file
+        #[arg(long)]
+        coverage: Option<String>,
+    },
+    /// Combine coverage files from several sessions into one
+    MergeCoverage { output: String, inputs: Vec<String> },
+    /// Search for the eighth register value that passes the teleporter's confirmation
+    CalculateTeleporterNumber {
+        #[arg(long, default_value_t = 4)]
+        a: u16,
+
+        #[arg(long, default_value_t = 1)]
+        b: u16,
+
+        /// The result the confirmation has to come out as
+        #[arg(long, default_value_t = 6)]
+        target: u16,
+
+        /// Register values to try, end exclusive
+        #[arg(long, value_parser = trace::parse_range, default_value = "0..32768")]
+        range: std::ops::Range<u16>,
+
+        /// Stop at the lowest value that works
+        #[arg(long)]
+        first_only: bool,
+
+        /// Run the confirmation routine lifted from the binary instead of the hand translation
+        #[arg(long)]
+        lifted: bool,
+    },
+    /// Lift the teleporter's confirmation routine out of the binary and compare it with the
+    /// hand translation for small arguments
+    CheckConfirmation {
+        /// Address of the routine, found automatically if not given
+        #[arg(long)]
+        entry: Option<u16>,
+
+        #[arg(long, default_value_t = 25734)]
+        magic: u16,
+
+        /// Compare f(a, b) for every a up to this
+        #[arg(long, default_value_t = 3)]
+        max_a: u16,
+
+        /// Compare f(a, b) for every b up to this
+        #[arg(long, default_value_t = 5)]
+        max_b: u16,
+    },
+    /// Play up to the vault lock, read its grid from the rooms, and find the route through it
+    SolveMaze {
+        /// Solve a grid from this file instead, laid out like `[22] - 9 *` per row
+        #[arg(long)]
+        grid: Option<String>,
+
+        /// Let the orb's weight wrap modulo 32768 instead of shattering
+        #[arg(long)]
+        modular: bool,
+
+        /// Most moves the hourglass allows to reach the vault door, or 0 for no limit.
+        /// Defaults to the game's limit for its own maze, and none for `--grid`
+        #[arg(long)]
+        hourglass: Option<u8>,
+
+        /// List every cheapest route rather than just one
+        #[arg(long)]
+        all: bool,
+
+        /// Cost of moving in each direction, like `north=2,east=1`. Other moves cost 1.
+        #[arg(long, value_parser = orb_maze::parse_weights)]
+        weights: Option<Weights>,
+
+        /// Check the moves in this replay fragment instead of finding a route, and with
+        /// `--verify` play them in the VM too
+        #[arg(long)]
+        check: Option<String>,
+
+        /// Append the route's commands to this replay file
+        #[arg(long)]
+        append: Option<String>,
+
+        /// Play each route in the VM from the antechamber, failing unless the vault door opens
+        #[arg(long)]
+        verify: bool,
+    },
+    /// Collect the coins, read their values in the game, and find the order that balances
+    /// the monument's equation
+    SolveCoins,
+    /// Serve the VM to a debugger frontend over the GDB remote serial protocol
+    Gdbserver {
+        #[arg(long, default_value = "127.0.0.1:1234")]
+        listen: String,
+    },
+    /// Serve the VM to an editor over the Debug Adapter Protocol on stdin/stdout
+    Dap,
+    /// Play through a file of commands headlessly, printing the game's output with each
+    /// command. Lines like `? Taken.` check the last command's output instead.
+    Transcript {
+        #[arg(default_value = "replay.txt")]
+        replay: String,
+
+        /// Print the rooms and inventories seen as JSON lines instead of the raw text
+        #[arg(long)]
+        parsed: bool,
+    },
+    /// Map every room reachable through exits, with the shortest path to each
+    Explore {
+        /// File of commands to play before exploring, e.g. to pick up a lantern
+        #[arg(long)]
+        prefix: Option<String>,
+
+        #[arg(long, default_value = "map.dot")]
+        dot: String,
+
+        #[arg(long, default_value = "map.json")]
+        json: String,
+    },
+    /// Find commands that collect every reachable item in the fewest moves, printing them in
+    /// replay format
+    PlanItems,
+    /// Play the whole game, writing the commands that reach the final code to a replay file
+    Solve {
+        #[arg(long, default_value = "replay.txt")]
+        output: String,
+
+        /// Swap in the teleporter's confirmation lifted from the binary, with this in the
+        /// eighth register, instead of patching around it
+        #[arg(long)]
+        eighth_register: Option<u16>,
+    },
+    /// Report where two trace files first diverge
+    TraceDiff {
+        a: String,
+        b: String,
+
+        /// Number of common steps and instructions to show around the divergence
+        #[arg(long, default_value_t = 5)]
+        context: usize,
+    },
 }
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[arg(value_enum, default_value_t=Command::Run)]
-    command: Command,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// File of `name = address [type [length]]` lines naming routines and memory cells
+    #[arg(long, global = true)]
+    symbols: Option<String>,
+
+    /// Load this challenge binary instead of the built-in one
+    #[arg(long, global = true)]
+    binary: Option<String>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 struct VM {
     pc: u16,
     registers: [u16; 8],
@@ -47,53 +303,400 @@ impl Default for VM {
     }
 }
 
-fn dump(binary: &[u16]) {
+impl VM {
+    /// A VM with the challenge binary loaded
+    fn challenge() -> Self {
+        Self::from_binary(include_bytes!("challenge.bin"))
+    }
+
+    fn load(path: &str) -> Self {
+        let Ok(binary) = std::fs::read(path) else {
+            panic!("Failed to read file: {path}");
+        };
+        Self::from_binary(&binary)
+    }
+
+    fn from_binary(binary: &[u8]) -> Self {
+        let mut vm = Self::default();
+        vm.memory.iter_mut().set_from(
+            binary
+                .iter()
+                .tuples()
+                .map(|(l, r)| [*l, *r])
+                .map(u16::from_le_bytes),
+        );
+        vm
+    }
+}
+
+fn dump(binary: &[u16], symbols: &Symbols, coverage: Option<&Coverage>) {
     let mut pos = 0;
     let mut ops = Vec::new();
+    let mut call_targets = BTreeSet::new();
     while pos < binary.len() as u16 {
         let Some((instruction, size)) = parse(binary, pos) else {
             pos += 1;
             continue;
         };
-        ops.push((instruction.to_string(), pos));
+        if instruction.opcode() == Call::OPCODE {
+            call_targets.extend(instruction.address_operands());
+        }
+        let annotation = symbols.annotate(instruction.address_operands());
+        ops.push((format!("{instruction}{annotation}"), pos));
         pos += size;
     }
-    for (_, group) in &ops.into_iter().group_by(|(text, _)| text.clone()) {
+    let functions = (symbols.iter())
+        .filter(|symbol| symbol.kind == SymbolKind::Function)
+        .map(|symbol| symbol.address)
+        .chain(call_targets)
+        .collect::<BTreeSet<_>>();
+    let unreached = |pos: u16| {
+        coverage.is_some_and(|coverage| functions.contains(&pos) && !coverage.contains(pos))
+    };
+    let marker = |pos: u16| match coverage {
+        Some(coverage) if coverage.contains(pos) => "+ ",
+        Some(_) => "- ",
+        None => "",
+    };
+    // Labelled addresses are never folded into a group with their neighbours
+    let key = |(text, pos): &(String, u16)| {
+        let labelled = symbols.at(*pos).is_some() || unreached(*pos);
+        (text.clone(), labelled.then_some(*pos), marker(*pos))
+    };
+    for (_, group) in &ops.iter().group_by(|op| key(op)) {
         let items = group.collect_vec();
-        let print = |(text, size): &(String, u16)| println!("{size}: {text}");
+        let print = |(text, pos): &(String, u16)| {
+            if let Some(symbol) = symbols.at(*pos) {
+                println!("\n{}: ; {}", symbol.name, symbol.kind);
+            }
+            if unreached(*pos) {
+                println!("\n; never executed function");
+            }
+            println!("{}{pos}: {text}", marker(*pos))
+        };
         if items.len() > 3 {
             print(items.first().unwrap());
             println!("...");
             print(items.last().unwrap());
             continue;
         }
-        for item in &items {
+        for item in items {
             print(item);
         }
     }
+    if let Some(coverage) = coverage {
+        let executed = ops
+            .iter()
+            .filter(|(_, pos)| coverage.contains(*pos))
+            .count();
+        println!("\nExecuted {executed} of {} instructions", ops.len());
+        println!("Never executed functions:");
+        for &function in functions.iter().filter(|&&function| unreached(function)) {
+            println!("    {}", symbols.describe(function));
+        }
+    }
 }
 
 fn main() {
-    let mut vm = VM::default();
-    vm.memory.iter_mut().set_from(
-        include_bytes!("challenge.bin")
-            .iter()
-            .tuples()
-            .map(|(l, r)| [*l, *r])
-            .map(u16::from_le_bytes),
-    );
-    let mut debugger = Debugger::new();
-    match Args::parse().command {
-        Command::Run => {
+    let args = Args::parse();
+    let mut vm = match &args.binary {
+        Some(path) => VM::load(path),
+        None => VM::challenge(),
+    };
+    let symbols = match &args.symbols {
+        Some(path) => Symbols::load(path),
+        None => Symbols::default(),
+    };
+    let command = args.command.unwrap_or_else(|| Command::Run {
+        instrumentation: Instrumentation::default(),
+    });
+    match command {
+        Command::Run { instrumentation } => {
             let mut side_effects = BasicSideEffects::default();
+            let mut debugger = Debugger::new(symbols);
+            instrumentation.attach(&mut debugger, &vm);
             debugger.run(&mut vm, &mut side_effects);
         }
-        Command::Debug => {
+        Command::Debug {
+            script,
+            instrumentation,
+        } => {
             let mut side_effects = FileBackedEffects::new("replay.txt");
+            let mut debugger = Debugger::new(symbols);
+            instrumentation.attach(&mut debugger, &vm);
+            if let Some(path) = script {
+                debugger.source(&mut vm, &path);
+            }
             debugger.debug(&mut vm, &mut side_effects);
         }
-        Command::DumpBinary => dump(&vm.memory),
-        Command::CalculateTeleporterNumber => Teleporter::run(),
-        Command::SolveMaze => Maze::solve(),
+        Command::DumpBinary { coverage } => {
+            let coverage = coverage.map(|path| Coverage::load(&path));
+            dump(&vm.memory, &symbols, coverage.as_ref())
+        }
+        Command::MergeCoverage { output, inputs } => {
+            let mut coverage = Coverage::default();
+            for path in &inputs {
+                coverage.merge(&Coverage::load(path));
+            }
+            coverage.save(&output);
+            println!("{} addresses executed", coverage.len());
+        }
+        Command::CalculateTeleporterNumber {
+            a,
+            b,
+            target,
+            range,
+            first_only,
+            lifted,
+        } => {
+            if !lifted {
+                let confirm = |magic| Teleporter::new(magic).check(a, b);
+                Teleporter::run((a, b), target, range, first_only, confirm);
+                return;
+            }
+            let Some(routine) = Routine::find_confirmation(&vm.memory) else {
+                panic!("Failed to find the confirmation routine");
+            };
+            println!("Running the routine at {}", routine.entry);
+            // Each magic number gets a fresh copy, so memos don't pile up across the range
+            let confirm = |magic| {
+                let result = routine.clone().confirm(magic, a, b);
+                result.unwrap_or_else(|err| panic!("Failed to run the confirmation: {err}"))
+            };
+            Teleporter::run((a, b), target, range, first_only, confirm);
+        }
+        Command::CheckConfirmation {
+            entry,
+            magic,
+            max_a,
+            max_b,
+        } => {
+            let mut routine = match entry {
+                Some(entry) => Routine::lift(&vm.memory, entry)
+                    .unwrap_or_else(|err| panic!("Failed to lift the routine: {err}")),
+                None => Routine::find_confirmation(&vm.memory)
+                    .expect("Failed to find the confirmation routine"),
+            };
+            let registers = |regs: &[usize]| regs.iter().map(|reg| format!("reg{reg}")).join(", ");
+            println!("Routine at {}", routine.entry);
+            println!("  reads {}", registers(&routine.inputs));
+            println!("  writes {}", registers(&routine.outputs));
+            let mut mismatches = 0;
+            for (a, b) in (0..=max_a).cartesian_product(0..=max_b) {
+                let lifted = routine
+                    .confirm(magic, a, b)
+                    .unwrap_or_else(|err| panic!("Failed to run the routine: {err}"));
+                let translated = Teleporter::new(magic).check(a, b);
+                if lifted != translated {
+                    println!("f({a}, {b}) = {lifted}, but the hand translation gives {translated}");
+                    mismatches += 1;
+                }
+            }
+            match mismatches {
+                0 => println!(
+                    "The hand translation matches for every f(a, b) up to f({max_a}, {max_b})"
+                ),
+                n => println!("{n} mismatches"),
+            }
+        }
+        Command::SolveMaze {
+            grid,
+            modular,
+            hourglass,
+            all,
+            weights,
+            check,
+            append,
+            verify,
+        } => {
+            let antechamber = (grid.is_none() || verify)
+                .then(|| Solver::to_vault_lock(Game::from_vm(vm)).game().clone());
+            let mut maze = match (&grid, &antechamber) {
+                (Some(path), _) => Maze::load(path),
+                (None, Some(game)) => Maze::read(game).expect("Failed to read the vault lock"),
+                (None, None) => unreachable!(),
+            };
+            if modular {
+                maze.rules.arithmetic = Arithmetic::Modular;
+            }
+            if let Some(hourglass) = hourglass {
+                maze.rules.hourglass = (hourglass > 0).then_some(hourglass);
+            }
+            if let Some(path) = check {
+                let Ok(text) = std::fs::read_to_string(&path) else {
+                    panic!("Failed to read file: {path}");
+                };
+                let commands = text.lines().map(String::from).collect_vec();
+                match maze.verify(&commands) {
+                    Ok(value) => println!("The vault door opens with the orb weighing {value}"),
+                    Err(err) => println!("{err}"),
+                }
+                if let Some(game) = antechamber.as_ref().filter(|_| verify) {
+                    match orb_maze::check_commands_in_game(game, &commands) {
+                        Ok(()) => println!("Verified in the VM: the vault door opens"),
+                        Err(err) => println!("In the VM: {err}"),
+                    }
+                }
+                return;
+            }
+            let limit = if all { orb_maze::MAX_ROUTES } else { 1 };
+            let routes = maze.routes(&weights.unwrap_or_default(), limit);
+            if routes.is_empty() {
+                println!("No route reaches the vault door");
+            }
+            for (n, route) in routes.iter().enumerate() {
+                if all {
+                    println!("\n# Route {}", n + 1);
+                }
+                maze.print(route);
+                if let Some(game) = antechamber.as_ref().filter(|_| verify) {
+                    if let Err(err) = orb_maze::check_in_game(game, route) {
+                        panic!("Failed to open the vault door in the VM. {err}");
+                    }
+                    println!("Verified in the VM: the vault door opens");
+                }
+            }
+            if let (Some(path), Some(route)) = (append, routes.first()) {
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path);
+                let Ok(mut file) = file else {
+                    panic!("Failed to open {path} for appending");
+                };
+                if file
+                    .write_all(orb_maze::replay_fragment(route).as_bytes())
+                    .is_err()
+                {
+                    panic!("Failed to append the route to {path}");
+                }
+            }
+        }
+        Command::SolveCoins => {
+            let mut game = Game::from_vm(vm);
+            game.run();
+            let mut game = Plan::collect_items(&game, &[]).game;
+            let map = WorldMap::explore(&game, game.look());
+            let Some(monument) = CoinPuzzle::find_monument(&map) else {
+                panic!("Failed to find the monument");
+            };
+            for command in &monument.path {
+                game.send(command);
+            }
+            let Some(puzzle) = CoinPuzzle::read(&game) else {
+                panic!("Failed to read the coins");
+            };
+            for (coin, value) in &puzzle.coins {
+                println!("{coin}: {value}");
+            }
+            match puzzle.solve() {
+                Some(order) => order.iter().for_each(|coin| println!("use {coin}")),
+                None => println!("No order of the coins balances the equation"),
+            }
+        }
+        Command::Gdbserver { listen } => {
+            let mut side_effects = FileBackedEffects::new("replay.txt");
+            let mut debugger = Debugger::new(symbols);
+            GdbServer::serve(&listen, &mut debugger, &mut vm, &mut side_effects);
+        }
+        Command::Dap => DapServer::serve(&mut Debugger::new(symbols), &mut vm),
+        Command::Transcript { replay, parsed } => {
+            let Ok(commands) = std::fs::read_to_string(&replay) else {
+                panic!("Failed to read file: {replay}");
+            };
+            let show = |command: Option<&str>, reply: &Reply| {
+                if !parsed {
+                    if let Some(command) = command {
+                        println!("> {command}");
+                    }
+                    print!("{reply}");
+                    return;
+                }
+                let room = Room::parse(&reply.text).map(|room| room.to_json());
+                let inventory = room::parse_inventory(&reply.text);
+                if room.is_some() || inventory.is_some() {
+                    let value = serde_json::json!({
+                        "command": command,
+                        "room": room,
+                        "inventory": inventory,
+                    });
+                    println!("{value}");
+                }
+            };
+            let mut game = Game::from_vm(vm);
+            let mut reply = game.run();
+            show(None, &reply);
+            for line in commands.lines() {
+                if let Some(text) = line.strip_prefix("? ") {
+                    reply = reply.expect(text);
+                    continue;
+                }
+                if game.halted() {
+                    break;
+                }
+                reply = game.send(line);
+                show(Some(line), &reply);
+            }
+        }
+        Command::Explore { prefix, dot, json } => {
+            let mut game = Game::from_vm(vm);
+            let mut reply = game.run();
+            let commands = match &prefix {
+                Some(path) => std::fs::read_to_string(path)
+                    .unwrap_or_else(|_| panic!("Failed to read file: {path}")),
+                None => String::new(),
+            };
+            for command in commands.lines() {
+                reply = game.send(command);
+            }
+            // After a prefix that didn't end by moving, look around
+            let start = Room::parse(&reply.text).unwrap_or_else(|| game.look());
+            let map = WorldMap::explore(&game, start);
+            let write = |path: &str, text: String| {
+                std::fs::write(path, text)
+                    .unwrap_or_else(|_| panic!("Failed to write file: {path}"))
+            };
+            write(&dot, map.to_dot());
+            write(&json, format!("{:#}\n", map.to_json()));
+            println!("Found {} rooms", map.rooms.len());
+        }
+        Command::PlanItems => {
+            let mut game = Game::from_vm(vm);
+            game.run();
+            let plan = Plan::collect_items(&game, &[]);
+            for (item, room) in &plan.items {
+                eprintln!("{item}: found in {room}");
+            }
+            for effect in &plan.effects {
+                eprintln!(
+                    "{} in {}: [{}] -> [{}]",
+                    effect.command,
+                    effect.room,
+                    effect.before.join(", "),
+                    effect.after.join(", ")
+                );
+            }
+            for command in &plan.commands {
+                println!("{command}");
+            }
+        }
+        Command::Solve {
+            output,
+            eighth_register,
+        } => {
+            let mut game = Game::from_vm(vm);
+            if let Some(magic) = eighth_register {
+                game.swap_confirmation(magic);
+            }
+            let solver = Solver::solve(game);
+            print!("{}", solver.transcript);
+            let replay = solver.commands.iter().map(|command| format!("{command}\n"));
+            std::fs::write(&output, replay.collect::<String>())
+                .unwrap_or_else(|_| panic!("Failed to write file: {output}"));
+            println!("\nWrote {} commands to {output}", solver.commands.len());
+        }
+        Command::TraceDiff { a, b, context } => {
+            trace_diff::trace_diff(&a, &b, &vm.memory, &symbols, context)
+        }
     }
 }
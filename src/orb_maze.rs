@@ -1,139 +1,527 @@
 use std::{
-    collections::{HashMap, VecDeque},
-    fmt::Display,
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
 };
 
-#[derive(Clone, Copy, Hash, PartialEq, Eq)]
-enum Operation {
-    Add,
-    Sub,
-    Mult,
-}
-
-impl Display for Operation {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                Operation::Add => '+',
-                Operation::Mult => '*',
-                Operation::Sub => '-',
+use itertools::Itertools;
+
+use crate::{explore::WorldMap, game::Game, room::Room};
+
+/// One more than the heaviest the orb can get
+const LIMIT: i64 = 32768;
+
+/// How many moves the game's hourglass allows between the antechamber and the
+/// vault door. Any longer and the game says "The hourglass has already run
+/// out" at the door; routes through the grid come in even lengths, so the
+/// next longest, 14 moves, is too many (see `test_check_in_game`).
+pub(crate) const HOURGLASS: u8 = 12;
+
+/// Most routes to list when asked for all of them
+pub(crate) const MAX_ROUTES: usize = 1000;
+
+/// Combines the orb's weight with the number on the floor, or `None` if it can't
+pub(crate) type Operator = fn(i64, i64) -> Option<i64>;
+
+/// What happens to the orb's weight when it goes out of range
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Arithmetic {
+    /// The orb shatters, as in the game
+    Orb,
+    /// Wraps modulo 32768, like the VM's own arithmetic
+    Modular,
+}
+
+/// How the symbols on the floor behave
+#[derive(Clone)]
+pub(crate) struct Rules {
+    pub(crate) arithmetic: Arithmetic,
+    /// Most moves allowed to reach the vault door, if there's a limit
+    pub(crate) hourglass: Option<u8>,
+    operators: HashMap<char, Operator>,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        let mut rules = Self {
+            arithmetic: Arithmetic::Orb,
+            hourglass: None,
+            operators: HashMap::new(),
+        };
+        rules.set_operator('+', |a, b| Some(a + b));
+        rules.set_operator('-', |a, b| Some(a - b));
+        rules.set_operator('*', |a, b| Some(a * b));
+        rules.set_operator('/', |a, b| a.checked_div(b));
+        rules
+    }
+}
+
+impl Rules {
+    pub(crate) fn set_operator(&mut self, symbol: char, operator: Operator) {
+        self.operators.insert(symbol, operator);
+    }
+
+    /// Operators that grid files can give a symbol of their own, by name
+    fn named_operator(name: &str) -> Option<Operator> {
+        let operator: Operator = match name {
+            "add" => |a, b| Some(a + b),
+            "sub" => |a, b| Some(a - b),
+            "mul" => |a, b| Some(a * b),
+            "div" => |a, b| a.checked_div(b),
+            "rem" => |a, b| a.checked_rem(b),
+            "pow" => |a, b| a.checked_pow(b.try_into().ok()?),
+            "and" => |a, b| Some(a & b),
+            "or" => |a, b| Some(a | b),
+            "xor" => |a, b| Some(a ^ b),
+            _ => return None,
+        };
+        Some(operator)
+    }
+
+    /// The orb's weight after carrying it onto `value` with `op` pending
+    fn apply(&self, op: char, weight: u16, value: u16) -> Option<u16> {
+        let operator = self.operators.get(&op)?;
+        let weight = operator(weight.into(), value.into())?;
+        match self.arithmetic {
+            Arithmetic::Orb => (0..LIMIT).contains(&weight).then_some(weight as u16),
+            Arithmetic::Modular => Some(weight.rem_euclid(LIMIT) as u16),
+        }
+    }
+}
+
+/// Cost of moving in each direction, by name like `north`. Other moves cost 1.
+pub(crate) type Weights = HashMap<String, u32>;
+
+/// Parses weights like `north=2,east=1`
+pub(crate) fn parse_weights(text: &str) -> Result<Weights, String> {
+    let items = text
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty());
+    items
+        .map(|item| {
+            let (direction, cost) = (item.split_once('='))
+                .ok_or_else(|| format!("Expected direction=cost, got {item:?}"))?;
+            if !["north", "south", "east", "west"].contains(&direction) {
+                return Err(format!("Unknown direction {direction:?}"));
             }
-        )
+            match cost.parse() {
+                Ok(cost) if cost > 0 => Ok((direction.into(), cost)),
+                _ => Err(format!("Invalid cost {cost:?}, expected a positive number")),
+            }
+        })
+        .collect()
+}
+
+/// A route through the lock, as each move and the state it leads to
+pub(crate) type Route = Vec<(&'static str, State)>;
+
+/// The commands to append to a replay file in the antechamber to open the vault
+pub(crate) fn replay_fragment(route: &Route) -> String {
+    let mut text = String::from("take orb\n");
+    for (instruction, _) in route {
+        text += instruction;
+        text += "\n";
+    }
+    text
+}
+
+/// Plays a route on a copy of a game waiting in the antechamber, and checks
+/// from the game's own output that the vault door opens
+pub(crate) fn check_in_game(game: &Game, route: &Route) -> Result<(), String> {
+    let fragment = replay_fragment(route);
+    check_commands_in_game(game, &fragment.lines().map(String::from).collect_vec())
+}
+
+/// Like `check_in_game`, for commands as `Maze::verify` takes them, taking
+/// the orb first if they don't
+pub(crate) fn check_commands_in_game(game: &Game, commands: &[String]) -> Result<(), String> {
+    let mut game = game.clone();
+    let mut transcript = String::new();
+    let mut last = String::new();
+    let take = (commands.first().map(String::as_str) != Some("take orb")).then_some("take orb");
+    let commands = commands.iter().map(String::as_str);
+    for command in take.into_iter().chain(commands).chain(["go vault"]) {
+        if game.halted() {
+            return Err(format!("The game halted before {command:?}:\n{transcript}"));
+        }
+        let reply = game.send(command);
+        transcript += &format!("> {command}\n{reply}");
+        last = reply.text.clone();
+        if reply.contains("The orb shatters") {
+            return Err(format!("The orb was lost at {command:?}:\n{transcript}"));
+        }
+        if reply.contains("The hourglass has already run out") {
+            return Err(format!(
+                "The hourglass ran out at {command:?}:\n{transcript}"
+            ));
+        }
+    }
+    match Room::parse(&last) {
+        Some(room) if room.title == "Vault" => Ok(()),
+        _ => Err(format!("The vault door didn't open:\n{transcript}")),
     }
 }
 
-#[derive(Clone, Copy, Hash, PartialEq, Eq)]
-struct State {
+#[derive(Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct State {
     coord: (usize, usize),
     value: u16,
-    op: Option<Operation>,
+    op: Option<char>,
+    /// Moves so far, counted only while the hourglass is running
+    moves: u8,
 }
 
 #[derive(Clone, Copy)]
 enum Node {
-    Op(Operation),
+    Op(char),
     Value(u16),
     Start,
     End(u16),
 }
 
+/// The vault lock: a grid of rooms, each with a number or an operator on
+/// the floor, that the orb has to be carried through so it weighs what the
+/// vault door asks for
 pub(crate) struct Maze {
     nodes: Vec<Vec<Node>>,
     width: isize,
     height: isize,
+    start: State,
+    end: State,
+    pub(crate) rules: Rules,
 }
 
 impl Maze {
-    pub(crate) fn solve() {
-        let start = State {
-            coord: (0, 3),
-            op: None,
-            value: 22,
-        };
-        let end = State {
-            coord: (3, 0),
-            op: None,
-            value: 30,
-        };
-        let maze = Maze::new();
-        let mut queue: VecDeque<(State, Option<State>)> = [(start, None)].into();
-        let mut parents: HashMap<State, State> = HashMap::new();
-        while let Some((state, parent)) = queue.pop_front() {
-            if parents.contains_key(&state) {
+    /// Prints each move of a route, with what it does to the orb
+    pub(crate) fn print(&self, route: &Route) {
+        println!("start # {}", self.start.value);
+        for (instruction, state) in route {
+            let description = match self.node(state.coord) {
+                Node::Start => unreachable!(),
+                Node::Op(op) => op.to_string(),
+                Node::End(val) | Node::Value(val) => format!("{val} = {}", state.value),
+            };
+            println!("{instruction} # {description}");
+        }
+        println!("end # {}", self.end.value);
+    }
+
+    /// The commands that carry the orb from the antechamber to the vault door
+    pub(crate) fn commands(&self) -> Option<Vec<String>> {
+        let route = self.route(&Weights::new())?;
+        Some(
+            (route.into_iter())
+                .map(|(instruction, _)| instruction.into())
+                .collect(),
+        )
+    }
+
+    /// The cheapest route under the weights
+    pub(crate) fn route(&self, weights: &Weights) -> Option<Route> {
+        self.routes(weights, 1).pop()
+    }
+
+    /// Up to `limit` routes, all as cheap as the cheapest
+    pub(crate) fn routes(&self, weights: &Weights, limit: usize) -> Vec<Route> {
+        let (parents, ends) = self.search(weights);
+        let mut routes = Vec::new();
+        // Walks back from the end through every cheapest way into each state
+        let mut stack = ends.into_iter().map(|end| (end, Vec::new())).collect_vec();
+        while let Some((state, mut route)) = stack.pop() {
+            if routes.len() == limit {
+                break;
+            }
+            if state == self.start {
+                route.reverse();
+                routes.push(route);
                 continue;
             }
-            if let Some(parent) = parent {
-                parents.insert(state, parent);
+            for &parent in parents.get(&state).into_iter().flatten() {
+                let mut route = route.clone();
+                route.push((direction(parent.coord, state.coord), state));
+                stack.push((parent, route));
             }
-            if state == end {
-                break;
-            }
-            if let Node::End(_) = &maze.nodes[state.coord.1][state.coord.0] {
-                continue; // Can't go anywhere else from the end
+        }
+        routes
+    }
+
+    /// Simulates carrying the orb from the antechamber along some commands,
+    /// after an optional `take orb`, returning its weight at the vault door
+    pub(crate) fn verify(&self, commands: &[String]) -> Result<u16, String> {
+        let moves = match commands.split_first() {
+            Some((first, rest)) if first == "take orb" => rest,
+            _ => commands,
+        };
+        let mut state = self.start;
+        for (n, command) in moves.iter().enumerate() {
+            let fail = |reason: &str| Err(format!("Move {} ({command:?}) {reason}", n + 1));
+            if let Node::End(_) = self.node(state.coord) {
+                return fail("leaves the vault door");
             }
-            for (x, y) in maze.neighbours(state.coord) {
-                let mut child = State {
-                    coord: (x, y),
-                    op: None,
-                    value: state.value,
+            let next = (self.neighbours(state.coord))
+                .find(|&coord| direction(state.coord, coord) == command);
+            let Some(coord) = next else {
+                return fail("doesn't lead to another room of the lock");
+            };
+            state = match self.step(state, coord) {
+                Ok(state) => state,
+                Err(reason) => return fail(reason),
+            };
+        }
+        if !self.opens(state) {
+            let place = match self.node(state.coord) {
+                Node::End(_) => "at the vault door",
+                _ => "short of the vault door",
+            };
+            return Err(format!(
+                "The orb weighs {} {place}, but the door needs {}",
+                state.value, self.end.value
+            ));
+        }
+        Ok(state.value)
+    }
+
+    /// Reads the grid from a game waiting in the antechamber, by walking its
+    /// rooms and reading the mosaic on each floor. The orb's starting value is
+    /// carved into its pedestal, and the value it must reach into the vault door.
+    /// The game's hourglass limits the moves to `HOURGLASS`.
+    pub(crate) fn read(game: &Game) -> Option<Self> {
+        let map = WorldMap::explore(game, game.look());
+        let rooms = (map.rooms.iter()).filter_map(|room| {
+            let offset = offset(&room.path)?;
+            Some((offset, room.room.description.as_str()))
+        });
+        let mut maze = Self::from_rooms(rooms)?;
+        maze.rules.hourglass = Some(HOURGLASS);
+        Some(maze)
+    }
+
+    pub(crate) fn load(path: &str) -> Self {
+        let Ok(text) = std::fs::read_to_string(path) else {
+            panic!("Failed to read file: {path}");
+        };
+        Self::parse(&text).unwrap_or_else(|err| panic!("Failed to parse {path}: {err}"))
+    }
+
+    /// Parses a grid laid out as on the map, north at the top, one row per
+    /// line. Cells are numbers or operator symbols, `[22]` is the antechamber
+    /// with the orb's starting weight, and `1=30` is the vault door's number
+    /// with the weight it asks for. Symbols besides `+ - * /` are declared on
+    /// a line of their own, like `op ^ xor` (see `Rules::named_operator`).
+    /// `#` starts a comment.
+    pub(crate) fn parse(text: &str) -> Result<Self, String> {
+        let mut cells = HashMap::new();
+        let mut start = None;
+        let mut end = None;
+        let mut rules = Rules::default();
+        let lines = (text.lines())
+            .map(|line| line.split('#').next().unwrap_or_default())
+            .filter(|line| !line.trim().is_empty());
+        let (declarations, rows): (Vec<_>, Vec<_>) =
+            lines.partition(|line| line.trim_start().starts_with("op "));
+        for declaration in declarations {
+            let Some((_, symbol, name)) = declaration.split_whitespace().collect_tuple() else {
+                return Err(format!("Expected op <symbol> <name>, got {declaration:?}"));
+            };
+            let (Ok(symbol), Some(operator)) =
+                (symbol.parse::<char>(), Rules::named_operator(name))
+            else {
+                return Err(format!("Invalid operator declaration {declaration:?}"));
+            };
+            rules.set_operator(symbol, operator);
+        }
+        for (y, row) in rows.into_iter().enumerate() {
+            for (x, cell) in row.split_whitespace().enumerate() {
+                let coord = (x as isize, y as isize);
+                let number = |text: &str| {
+                    (text.parse()).map_err(|_| format!("Invalid number {text:?} in row {}", y + 1))
                 };
-                match &maze.nodes[y][x] {
-                    Node::Start => continue,
-                    Node::Op(op) => child.op = Some(*op),
-                    Node::End(val) | Node::Value(val) => {
-                        child.value = match state.op {
-                            Some(Operation::Add) => state.value + val,
-                            Some(Operation::Mult) => state.value * val,
-                            Some(Operation::Sub) => state.value - val,
-                            None => unreachable!(),
+                let node = if let Some(value) = cell.strip_prefix('[') {
+                    start = Some((coord, number(value.trim_end_matches(']'))?));
+                    Node::Start
+                } else if let Some((value, target)) = cell.split_once('=') {
+                    end = Some((coord, number(target)?));
+                    Node::End(number(value)?)
+                } else if cell.starts_with(|c: char| c.is_ascii_digit()) {
+                    Node::Value(number(cell)?)
+                } else {
+                    let mut symbol = cell.chars();
+                    match (symbol.next(), symbol.next()) {
+                        (Some(op), None) if rules.operators.contains_key(&op) => Node::Op(op),
+                        (Some(_), None) => {
+                            return Err(format!(
+                                "Unknown operator {cell:?} in row {}, column {}",
+                                y + 1,
+                                x + 1
+                            ))
                         }
+                        _ => return Err(format!("Invalid cell {cell:?} in row {}", y + 1)),
                     }
-                }
-                queue.push_back((child, Some(state)));
+                };
+                cells.insert(coord, node);
             }
         }
-        let mut moves = Vec::new();
-        let mut state = end;
-        while let Some(&parent) = parents.get(&state) {
-            let instruction = if parent.coord.0 < state.coord.0 {
-                "go east"
-            } else if parent.coord.0 > state.coord.0 {
-                "go west"
-            } else if parent.coord.1 < state.coord.1 {
-                "go south"
+        let start = start.ok_or("No antechamber, like [22]")?;
+        let end = end.ok_or("No vault door, like 1=30")?;
+        let mut maze = Self::from_cells(cells, start, end).ok_or("Rows have different lengths")?;
+        maze.rules = rules;
+        Ok(maze)
+    }
+
+    /// Builds the grid from each room's description and where it is from the
+    /// first room, which is the antechamber, as (east, south)
+    fn from_rooms<'a>(rooms: impl IntoIterator<Item = ((isize, isize), &'a str)>) -> Option<Self> {
+        let mut cells = HashMap::new();
+        let mut start = None;
+        let mut end = None;
+        for (coord, description) in rooms {
+            let (text, mosaic) =
+                (description.split_once("mosaic depicting")).unwrap_or((description, ""));
+            let carved = quoted(text).and_then(|value| value.parse().ok());
+            let node = if start.is_none() {
+                start = Some((coord, carved?));
+                Node::Start
             } else {
-                "go north"
-            };
-            let node = &maze.nodes[state.coord.1][state.coord.0];
-            let description = match node {
-                Node::Start => unreachable!(),
-                Node::Op(op) => op.to_string(),
-                Node::End(val) | Node::Value(val) => format!("{val} = {}", state.value),
+                let Some(symbol) = quoted(mosaic) else {
+                    continue;
+                };
+                let node = match symbol.parse() {
+                    Ok(value) => Node::Value(value),
+                    Err(_) => Node::Op(symbol.chars().exactly_one().ok()?),
+                };
+                match (node, carved) {
+                    (Node::Value(val), Some(target)) => {
+                        end = Some((coord, target));
+                        Node::End(val)
+                    }
+                    (node, _) => node,
+                }
             };
-            moves.push(format!("{instruction} # {description}"));
-            state = parent;
+            cells.insert(coord, node);
         }
-        println!("start # {}", start.value);
-        for instruction in moves.iter().rev() {
-            println!("{instruction}");
+        Self::from_cells(cells, start?, end?)
+    }
+
+    /// Lays out cells into a full grid, if they fill one
+    fn from_cells(
+        cells: HashMap<(isize, isize), Node>,
+        start: ((isize, isize), u16),
+        end: ((isize, isize), u16),
+    ) -> Option<Self> {
+        let (min_x, max_x) = cells.keys().map(|&(x, _)| x).minmax().into_option()?;
+        let (min_y, max_y) = cells.keys().map(|&(_, y)| y).minmax().into_option()?;
+        let nodes = (min_y..=max_y)
+            .map(|y| {
+                (min_x..=max_x)
+                    .map(|x| cells.get(&(x, y)).copied())
+                    .collect::<Option<Vec<_>>>()
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let state = |((x, y), value): ((isize, isize), u16)| State {
+            coord: ((x - min_x) as usize, (y - min_y) as usize),
+            op: None,
+            value,
+            moves: 0,
+        };
+        Some(Self {
+            nodes,
+            width: max_x - min_x + 1,
+            height: max_y - min_y + 1,
+            start: state(start),
+            end: state(end),
+            rules: Rules::default(),
+        })
+    }
+
+    fn node(&self, (x, y): (usize, usize)) -> Node {
+        self.nodes[y][x]
+    }
+
+    /// Whether the orb is at the vault door with the weight it asks for
+    fn opens(&self, state: State) -> bool {
+        (state.coord, state.value) == (self.end.coord, self.end.value)
+    }
+
+    /// Searches cheapest first, returning every state each state is reached
+    /// from at its lowest cost, and the cheapest states that open the door
+    fn search(&self, weights: &Weights) -> (HashMap<State, Vec<State>>, Vec<State>) {
+        let mut costs = HashMap::from([(self.start, 0)]);
+        let mut parents: HashMap<State, Vec<State>> = HashMap::new();
+        let mut queue = BinaryHeap::from([Reverse((0, self.start))]);
+        let mut ends = Vec::new();
+        let mut best = None;
+        while let Some(Reverse((cost, state))) = queue.pop() {
+            if cost > costs[&state] {
+                continue; // Already reached more cheaply
+            }
+            if best.is_some_and(|best| cost > best) {
+                break;
+            }
+            if self.opens(state) {
+                best = Some(cost);
+                ends.push(state);
+                continue;
+            }
+            for (instruction, child) in self.steps(state) {
+                let direction = instruction.trim_start_matches("go ");
+                let cost = cost + weights.get(direction).copied().unwrap_or(1);
+                match costs.get(&child) {
+                    Some(&best) if best < cost => {}
+                    Some(&best) if best == cost => parents.entry(child).or_default().push(state),
+                    _ => {
+                        costs.insert(child, cost);
+                        parents.insert(child, vec![state]);
+                        queue.push(Reverse((cost, child)));
+                    }
+                }
+            }
         }
-        println!("end # {}", end.value);
-    }
-
-    fn new() -> Self {
-        use Node::{End, Op, Start, Value};
-        use Operation::{Add, Mult, Sub};
-        Self {
-            nodes: vec![
-                vec![Op(Mult), Value(8), Op(Sub), End(1)],
-                vec![Value(4), Op(Mult), Value(11), Op(Mult)],
-                vec![Op(Add), Value(4), Op(Sub), Value(18)],
-                vec![Start, Op(Sub), Value(9), Op(Mult)],
-            ],
-            width: 4,
-            height: 4,
+        (parents, ends)
+    }
+
+    /// The moves from a state, pruned when the orb can't survive them, when
+    /// they'd pass back through the antechamber (which resets the orb) or
+    /// when they'd leave the vault door
+    fn steps(&self, state: State) -> impl Iterator<Item = (&'static str, State)> + '_ {
+        let at_end = matches!(self.node(state.coord), Node::End(_));
+        (self.neighbours(state.coord))
+            .filter(move |_| !at_end)
+            .filter_map(move |coord| {
+                let child = self.step(state, coord).ok()?;
+                Some((direction(state.coord, coord), child))
+            })
+    }
+
+    /// Carries the orb into a neighbouring room
+    fn step(&self, state: State, coord: (usize, usize)) -> Result<State, &'static str> {
+        let moves = match self.rules.hourglass {
+            Some(limit) if state.moves >= limit => {
+                return Err("comes after the hourglass runs out")
+            }
+            Some(_) => state.moves + 1,
+            None => 0,
+        };
+        match (self.node(coord), state.op) {
+            (Node::Start, _) => Err("goes back to the antechamber, which resets the orb"),
+            (Node::Op(op), _) => Ok(State {
+                coord,
+                op: Some(op),
+                value: state.value,
+                moves,
+            }),
+            (Node::End(val) | Node::Value(val), Some(op)) => {
+                let value = self.rules.apply(op, state.value, val);
+                let value = value.ok_or("shatters the orb")?;
+                Ok(State {
+                    coord,
+                    op: None,
+                    value,
+                    moves,
+                })
+            }
+            (Node::End(_) | Node::Value(_), None) => {
+                Err("steps onto a number with no symbol before it")
+            }
         }
     }
 
@@ -150,3 +538,169 @@ impl Maze {
             })
     }
 }
+
+/// The command that moves between neighbouring rooms
+fn direction(from: (usize, usize), to: (usize, usize)) -> &'static str {
+    if from.0 < to.0 {
+        "go east"
+    } else if from.0 > to.0 {
+        "go west"
+    } else if from.1 < to.1 {
+        "go south"
+    } else {
+        "go north"
+    }
+}
+
+/// The first quoted value in some text, like `4` in "depicting the number '4'"
+fn quoted(text: &str) -> Option<&str> {
+    text.split('\'').nth(1)
+}
+
+/// Where a path of moves leads, as (east, south), if it's only moves
+fn offset(path: &[String]) -> Option<(isize, isize)> {
+    path.iter().try_fold((0, 0), |(x, y), command| {
+        match command.strip_prefix("go ")? {
+            "north" => Some((x, y - 1)),
+            "south" => Some((x, y + 1)),
+            "east" => Some((x + 1, y)),
+            "west" => Some((x - 1, y)),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::solver::Solver;
+
+    #[test]
+    fn test_check_in_game() {
+        let solver = Solver::to_vault_lock(Game::new());
+        let antechamber = solver.game();
+        let mut maze = Maze::read(antechamber).unwrap();
+        let shortest = maze.route(&Weights::new()).unwrap();
+        assert_eq!(shortest.len(), HOURGLASS as usize);
+        assert_eq!(check_in_game(antechamber, &shortest), Ok(()));
+        let moves = shortest.iter().map(|(i, _)| i.to_string()).collect_vec();
+        assert_eq!(check_commands_in_game(antechamber, &moves), Ok(()));
+
+        // A route that only works without the hourglass runs out of sand in the game
+        maze.rules.hourglass = None;
+        let long = maze.route(&parse_weights("north=5").unwrap()).unwrap();
+        assert_eq!(long.len(), HOURGLASS as usize + 2);
+        let err = check_in_game(antechamber, &long).unwrap_err();
+        assert!(err.starts_with("The hourglass ran out"), "{err}");
+    }
+
+    #[test]
+    fn test_solve_grids() {
+        let game = Maze::parse(
+            "*    8  -  1=30\n\
+             4    *  11 *\n\
+             +    4  -  18\n\
+             [22] -  9  *",
+        )
+        .unwrap();
+        let commands = game.commands().unwrap();
+        assert_eq!(commands.len(), 12);
+        assert_eq!(commands.last().unwrap(), "go east");
+
+        // Grids from files aren't held to the game's hourglass
+        let long = Maze::parse("[1] + 1 + 1 + 1 + 1 + 1 + 1 + 1=8").unwrap();
+        assert_eq!(long.commands().unwrap().len(), 14);
+
+        let division = Maze::parse("/ 2=10 # 20 / 2\n[20] *").unwrap();
+        assert_eq!(division.commands().unwrap(), vec!["go north", "go east"]);
+
+        // Doubling 16384 only comes back to 0 if the weight wraps
+        let mut wrapping = Maze::parse("* 2=0\n[16384] -").unwrap();
+        assert_eq!(wrapping.commands(), None);
+        wrapping.rules.arithmetic = Arithmetic::Modular;
+        assert_eq!(wrapping.commands().unwrap(), vec!["go north", "go east"]);
+
+        let unknown = Maze::parse("^ 6=3\n[5] +").err().unwrap();
+        assert_eq!(unknown, "Unknown operator \"^\" in row 1, column 1");
+        let mut custom = Maze::parse("op ^ xor # 5 ^ 6 = 3\n^ 6=3\n[5] +").unwrap();
+        assert_eq!(custom.commands().unwrap(), vec!["go north", "go east"]);
+        custom.rules.set_operator('^', |a, b| Some(a + b));
+        assert_eq!(custom.commands(), None);
+        assert!(Maze::parse("op ^ nand\n^ 6=3\n[5] +").is_err());
+
+        assert!(Maze::parse("[1] + 2=3\n4").is_err());
+        assert!(Maze::parse("[1] ++ 2=3").is_err());
+    }
+
+    #[test]
+    fn test_routes() {
+        let commands = |route: &Route| route.iter().map(|(i, _)| i.to_string()).collect_vec();
+        let both_ways = Maze::parse("+ 1=6\n[5] +").unwrap();
+        let routes = both_ways.routes(&Weights::new(), MAX_ROUTES);
+        assert_eq!(routes.len(), 2);
+        for route in &routes {
+            assert_eq!(both_ways.verify(&commands(route)), Ok(6));
+        }
+
+        let mut game = Maze::parse(
+            "*    8  -  1=30\n\
+             4    *  11 *\n\
+             +    4  -  18\n\
+             [22] -  9  *",
+        )
+        .unwrap();
+        // Parsed grids have no hourglass, so weights can pick a longer route
+        assert_eq!(game.rules.hourglass, None);
+        let north = parse_weights("north=5").unwrap();
+        let norths = |route: &Route| route.iter().filter(|(i, _)| *i == "go north").count();
+        let shortest = game.route(&Weights::new()).unwrap();
+        let weighted = game.route(&north).unwrap();
+        assert!(norths(&weighted) < norths(&shortest));
+        assert!(weighted.len() > shortest.len());
+        let fragment = replay_fragment(&weighted);
+        let fragment = fragment.lines().map(String::from).collect_vec();
+        assert_eq!(game.verify(&fragment), Ok(30));
+        // With the game's hourglass only the shortest route fits, however moves are weighed
+        game.rules.hourglass = Some(HOURGLASS);
+        assert!(game.verify(&fragment).unwrap_err().contains("hourglass"));
+        assert_eq!(game.route(&north).unwrap().len(), shortest.len());
+
+        let fail = |moves: &[&str]| {
+            let moves = moves.iter().map(|m| m.to_string()).collect_vec();
+            game.verify(&moves).unwrap_err()
+        };
+        assert!(fail(&["go west"]).contains("doesn't lead"));
+        assert!(fail(&["go north", "go south"]).contains("antechamber"));
+        assert!(
+            fail(&["go east", "go east", "go west", "go east", "go west", "go east"])
+                .contains("shatters")
+        );
+        assert!(fail(&["go north"]).contains("short of the vault door"));
+        assert!(parse_weights("up=1").is_err());
+    }
+
+    #[test]
+    fn test_read_maze() {
+        let floor =
+            |symbol: &str| format!("The floor of this room is a large mosaic depicting {symbol}.");
+        let door = format!(
+            "The door has a large '12' carved into it.\n\n{}",
+            floor("the number '3'")
+        );
+        let rooms = [
+            (
+                (0, 0),
+                "The number '4' is carved into the orb's pedestal.".into(),
+            ),
+            ((0, -1), floor("a '*' symbol")),
+            ((1, 0), floor("a '+' symbol")),
+            ((1, -1), door),
+        ];
+        let maze = Maze::from_rooms(rooms.iter().map(|(coord, text)| (*coord, text.as_str())));
+        let maze = maze.unwrap();
+        assert_eq!((maze.start.value, maze.end.value), (4, 12));
+        assert_eq!(maze.end.coord, (1, 0));
+        assert_eq!(maze.commands().unwrap(), vec!["go north", "go east"]);
+        assert!(Maze::from_rooms([((0, 0), "No quotes here")]).is_none());
+    }
+}
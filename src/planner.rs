@@ -0,0 +1,264 @@
+use std::{collections::HashSet, iter::once};
+
+use itertools::Itertools;
+
+use crate::{
+    explore::{room_key, RoomKey, WorldMap},
+    game::Game,
+};
+
+/// How many `use` commands in a row to try when no items are reachable. The
+/// plan stops short of items that need a longer sequence.
+const MAX_USES: usize = 2;
+
+/// Most items to find the best order for at once. Any further away wait
+/// until nearer ones have been taken.
+const MAX_ORDERED: usize = 12;
+
+/// Stands in for the moves between places with no way from one to the other
+const UNREACHABLE: usize = 1 << 16;
+
+/// A command that was found to change the game's state
+pub(crate) struct Effect {
+    pub(crate) command: String,
+    /// Where the command was tried
+    pub(crate) room: String,
+    pub(crate) before: Vec<String>,
+    pub(crate) after: Vec<String>,
+}
+
+/// Commands that collect every item the game lets us reach, found by trying
+/// things out on snapshots. Items in reach are collected in the order that
+/// takes the fewest moves, going by the distances on the explored map, and
+/// when none are left, the shortest sequence of `use` commands (up to
+/// `MAX_USES`) that reveals more is played (so the can and lantern get the
+/// player through the dark).
+pub(crate) struct Plan {
+    pub(crate) commands: Vec<String>,
+    /// Each item taken, with the room it was found in
+    pub(crate) items: Vec<(String, String)>,
+    pub(crate) effects: Vec<Effect>,
+    /// The game once the commands have been played
+    pub(crate) game: Game,
+}
+
+impl Plan {
+    /// Plans from a game waiting for a command, leaving the `ignored` items where they are
+    pub(crate) fn collect_items(game: &Game, ignored: &[&str]) -> Self {
+        let mut plan = Self {
+            commands: Vec::new(),
+            items: Vec::new(),
+            effects: Vec::new(),
+            game: game.clone(),
+        };
+        // Things that can't be picked up, and where they are
+        let mut fixtures = HashSet::new();
+        // Things that were found to be takeable
+        let mut takeable = HashSet::new();
+        loop {
+            let map = WorldMap::explore(&plan.game, plan.game.look());
+            let mut items = Vec::new();
+            for (id, room) in map.rooms.iter().enumerate() {
+                for thing in &room.room.things {
+                    let key = (room.key(), thing.clone());
+                    if ignored.contains(&thing.as_str()) || fixtures.contains(&key) {
+                        continue;
+                    }
+                    if !takeable.contains(&key) {
+                        let mut next = plan.game.clone();
+                        for command in &room.path {
+                            next.send(command);
+                        }
+                        next.send(&format!("take {thing}"));
+                        if !next.inventory().contains(thing) {
+                            fixtures.insert(key);
+                            continue;
+                        }
+                        takeable.insert(key);
+                    }
+                    items.push((id, thing));
+                }
+            }
+            // Rooms are explored nearest first, so these are the nearest items
+            items.truncate(MAX_ORDERED);
+            // Where the player stands, then where each item is
+            let places = once(0).chain(items.iter().map(|&(id, _)| id)).collect_vec();
+            let distances = (places.iter())
+                .map(|&from| {
+                    let distances = map.distances(from);
+                    places.iter().map(|&to| distances[to]).collect_vec()
+                })
+                .collect_vec();
+            if let Some(&first) = visit_order(&distances).first() {
+                let (id, thing) = items[first];
+                let room = &map.rooms[id];
+                let mut next = plan.game.clone();
+                for command in &room.path {
+                    next.send(command);
+                }
+                let before = next.inventory();
+                let command = format!("take {thing}");
+                next.send(&command);
+                let after = next.inventory();
+                if !after.contains(thing) {
+                    fixtures.insert((room.key(), thing.clone()));
+                    continue;
+                }
+                if after.len() != before.len() + 1 {
+                    plan.effects.push(Effect {
+                        command: command.clone(),
+                        room: room.room.title.clone(),
+                        before,
+                        after,
+                    });
+                }
+                plan.commands.extend(room.path.iter().cloned());
+                plan.commands.push(command);
+                plan.items.push((thing.clone(), room.room.title.clone()));
+                plan.game = next;
+                continue;
+            }
+            match plan.unlock(&fixtures, ignored) {
+                Some((commands, game)) => {
+                    plan.commands.extend(commands);
+                    plan.game = game;
+                }
+                None => return plan,
+            }
+        }
+    }
+
+    /// Tries using items where the player stands, breadth first, until
+    /// something new comes into reach
+    fn unlock(
+        &mut self,
+        fixtures: &HashSet<(RoomKey, String)>,
+        ignored: &[&str],
+    ) -> Option<(Vec<String>, Game)> {
+        let mut frontier = vec![(Vec::new(), self.game.clone())];
+        for _ in 0..MAX_USES {
+            let mut next_frontier = Vec::new();
+            for (commands, game) in frontier {
+                let before = game.inventory();
+                let there = room_key(&game, &game.look());
+                for item in &before {
+                    let command = format!("use {item}");
+                    let mut next = game.clone();
+                    next.send(&command);
+                    if next.halted() {
+                        continue;
+                    }
+                    let after = next.inventory();
+                    let here = next.look();
+                    if after == before && room_key(&next, &here) == there {
+                        continue;
+                    }
+                    self.effects.push(Effect {
+                        command: command.clone(),
+                        room: here.title.clone(),
+                        before: before.clone(),
+                        after,
+                    });
+                    let mut commands = commands.clone();
+                    commands.push(command);
+                    let map = WorldMap::explore(&next, here);
+                    let reachable = (map.rooms.iter()).any(|room| {
+                        (room.room.things.iter()).any(|thing| {
+                            !ignored.contains(&thing.as_str())
+                                && !fixtures.contains(&(room.key(), thing.clone()))
+                        })
+                    });
+                    if reachable {
+                        return Some((commands, next));
+                    }
+                    next_frontier.push((commands, next));
+                }
+            }
+            frontier = next_frontier;
+        }
+        None
+    }
+}
+
+/// The order to visit places in that takes the fewest moves, given the
+/// moves from where the player stands (row 0) and from each place (the rest)
+/// to each other, or `None` where there's no way. Places are numbered from 0,
+/// leaving out where the player stands.
+fn visit_order(distances: &[Vec<Option<usize>>]) -> Vec<usize> {
+    let n = distances.len().saturating_sub(1);
+    if n == 0 {
+        return Vec::new();
+    }
+    let moves = |from: usize, to: usize| distances[from][to].unwrap_or(UNREACHABLE);
+    // The fewest moves to visit a set of places, ending at each one, and
+    // the place visited before it
+    let mut best = vec![vec![(usize::MAX, 0); n]; 1 << n];
+    for place in 0..n {
+        best[1 << place][place] = (moves(0, place + 1), place);
+    }
+    for visited in 1..1 << n {
+        for last in 0..n {
+            let (cost, _) = best[visited][last];
+            if cost == usize::MAX {
+                continue;
+            }
+            for next in (0..n).filter(|next| visited & 1 << next == 0) {
+                let cost = cost + moves(last + 1, next + 1);
+                let entry = &mut best[visited | 1 << next][next];
+                if cost < entry.0 {
+                    *entry = (cost, last);
+                }
+            }
+        }
+    }
+    let mut visited = (1 << n) - 1;
+    let mut last = (0..n).min_by_key(|&last| best[visited][last].0).unwrap();
+    let mut order = vec![last];
+    while visited != 1 << last {
+        let before = best[visited][last].1;
+        visited &= !(1 << last);
+        last = before;
+        order.push(last);
+    }
+    order.reverse();
+    order
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_visit_order() {
+        // Places along a corridor, at -2, 1 and 3 from where the player stands
+        let positions: [isize; 4] = [0, -2, 1, 3];
+        let distances = (positions.iter())
+            .map(|a| positions.iter().map(|b| Some(a.abs_diff(*b))).collect_vec())
+            .collect_vec();
+        // Nearest first goes 1, 3, then back to -2 in 8 moves; going to -2 first takes 7
+        assert_eq!(visit_order(&distances), vec![0, 1, 2]);
+        // Unless there's no way back from -2
+        let mut one_way = distances.clone();
+        one_way[1] = vec![None; 4];
+        assert_eq!(visit_order(&one_way).last(), Some(&0));
+        assert_eq!(visit_order(&[vec![Some(0)]]), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_collect_items() {
+        let mut game = Game::new();
+        game.run();
+        let plan = Plan::collect_items(&game, &[]);
+        let position = |command: &str| {
+            (plan.commands.iter())
+                .position(|c| c == command)
+                .unwrap_or_else(|| panic!("{command:?} isn't in the plan"))
+        };
+        assert!(position("take can") < position("use can"));
+        assert!(position("use can") < position("use lantern"));
+        let inventory = plan.game.inventory();
+        for coin in ["red", "corroded", "shiny", "concave", "blue"] {
+            assert!(inventory.contains(&format!("{coin} coin")));
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+use itertools::Itertools;
+
+use crate::symbols::Symbols;
+
+/// Number of addresses listed in the report
+const TOP_ADDRESSES: usize = 20;
+
+/// Counts instruction executions per address and per call stack. Call stacks
+/// are interned as a tree of nodes so counting an instruction stays cheap.
+pub(crate) struct Profiler {
+    path: String,
+    /// Executions of each address
+    hits: Vec<u64>,
+    /// The parent node and called function of each call stack, with the
+    /// program entry point as the root
+    nodes: Vec<(usize, u16)>,
+    children: HashMap<(usize, u16), usize>,
+    /// Instructions executed with each call stack
+    node_hits: Vec<u64>,
+    current: usize,
+}
+
+impl Profiler {
+    /// Folded stacks will be written to `path`
+    pub(crate) fn new(path: &str) -> Self {
+        Self {
+            path: path.into(),
+            hits: vec![0; 32768],
+            nodes: vec![(0, 0)],
+            children: HashMap::new(),
+            node_hits: vec![0],
+            current: 0,
+        }
+    }
+
+    /// Counts an execution of the instruction at pc
+    pub(crate) fn record(&mut self, pc: u16) {
+        self.hits[pc as usize] += 1;
+        self.node_hits[self.current] += 1;
+    }
+
+    pub(crate) fn enter(&mut self, target: u16) {
+        let next = self.nodes.len();
+        let node = *self.children.entry((self.current, target)).or_insert(next);
+        if node == next {
+            self.nodes.push((self.current, target));
+            self.node_hits.push(0);
+        }
+        self.current = node;
+    }
+
+    pub(crate) fn exit(&mut self) {
+        self.current = self.nodes[self.current].0;
+    }
+
+    /// The functions on a call stack, outermost first
+    fn stack(&self, mut node: usize) -> Vec<u16> {
+        let mut stack = vec![self.nodes[node].1];
+        while node != 0 {
+            node = self.nodes[node].0;
+            stack.push(self.nodes[node].1);
+        }
+        stack.reverse();
+        stack
+    }
+
+    /// Writes the folded stacks file and prints a report of the hottest
+    /// functions and addresses to stderr
+    pub(crate) fn finish(&self, symbols: &Symbols) {
+        let name = |addr: u16| match symbols.at(addr) {
+            Some(symbol) => symbol.name.clone(),
+            None => addr.to_string(),
+        };
+        let Ok(file) = File::create(&self.path) else {
+            panic!("Failed to open file for writing: {}", self.path);
+        };
+        let mut writer = BufWriter::new(file);
+        let mut self_hits = HashMap::<u16, u64>::new();
+        let mut total_hits = HashMap::<u16, u64>::new();
+        for (node, &hits) in self.node_hits.iter().enumerate() {
+            if hits == 0 {
+                continue;
+            }
+            let stack = self.stack(node);
+            writeln!(
+                writer,
+                "{} {hits}",
+                stack.iter().map(|&addr| name(addr)).join(";")
+            )
+            .expect("Failed to write profile");
+            *self_hits.entry(self.nodes[node].1).or_default() += hits;
+            // Recursive functions count once towards their own total
+            for function in stack.into_iter().collect::<HashSet<_>>() {
+                *total_hits.entry(function).or_default() += hits;
+            }
+        }
+        writer.flush().expect("Failed to write profile");
+
+        let executed: u64 = self.hits.iter().sum();
+        let percent = |hits: u64| 100.0 * hits as f64 / executed.max(1) as f64;
+        eprintln!("\nInstructions executed: {executed}");
+        eprintln!(
+            "\n{:>12} {:>6} {:>12} {:>6}  function",
+            "self", "%", "total", "%"
+        );
+        let functions = total_hits
+            .iter()
+            .sorted_by_key(|&(&function, &hits)| (std::cmp::Reverse(hits), function));
+        for (&function, &total) in functions {
+            let hits = self_hits.get(&function).copied().unwrap_or(0);
+            eprintln!(
+                "{hits:>12} {:>6.2} {total:>12} {:>6.2}  {}",
+                percent(hits),
+                percent(total),
+                symbols.describe(function)
+            );
+        }
+        eprintln!("\n{:>12} {:>6}  address", "count", "%");
+        let addresses = (self.hits.iter().enumerate())
+            .filter(|(_, &hits)| hits > 0)
+            .sorted_by_key(|&(addr, &hits)| (std::cmp::Reverse(hits), addr))
+            .take(TOP_ADDRESSES);
+        for (addr, &hits) in addresses {
+            eprintln!(
+                "{hits:>12} {:>6.2}  {}",
+                percent(hits),
+                symbols.describe(addr as u16)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_call_stacks() {
+        let mut profiler = Profiler::new("unused");
+        profiler.record(0);
+        profiler.enter(100);
+        profiler.record(100);
+        profiler.enter(200);
+        profiler.record(200);
+        profiler.record(201);
+        profiler.exit();
+        profiler.enter(200);
+        profiler.record(200);
+        profiler.exit();
+        profiler.exit();
+        profiler.exit();
+        profiler.record(1);
+
+        assert_eq!(profiler.nodes.len(), 3);
+        assert_eq!(profiler.node_hits, vec![2, 1, 3]);
+        assert_eq!(profiler.stack(2), vec![0, 100, 200]);
+        assert_eq!(profiler.hits[200], 2);
+    }
+}
@@ -0,0 +1,151 @@
+use serde_json::{json, Value};
+
+/// A room as the game describes it on entering or `look`:
+///
+/// ```text
+/// == Foothills ==
+/// You find yourself standing at the base of an enormous mountain. ...
+///
+/// Things of interest here:
+/// - tablet
+///
+/// There are 2 exits:
+/// - doorway
+/// - south
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub(crate) struct Room {
+    pub(crate) title: String,
+    /// Every paragraph between the title and the lists
+    pub(crate) description: String,
+    pub(crate) exits: Vec<String>,
+    pub(crate) things: Vec<String>,
+}
+
+impl Room {
+    /// Finds the room description in some game output, ignoring any messages
+    /// printed before it
+    pub(crate) fn parse(text: &str) -> Option<Self> {
+        let mut lines = text
+            .lines()
+            .skip_while(|line| !(line.starts_with("== ") && line.ends_with(" ==")));
+        let title = lines.next()?;
+        let mut room = Self {
+            title: title[3..title.len() - 3].into(),
+            ..Self::default()
+        };
+        let mut description = Vec::new();
+        let mut list = None;
+        for line in lines {
+            if line == "Things of interest here:" {
+                list = Some(&mut room.things);
+            } else if line.starts_with("There are ") || line == "There is 1 exit:" {
+                list = Some(&mut room.exits);
+            } else if line == "What do you do?" {
+                break;
+            } else if let Some(list) = &mut list {
+                if let Some(item) = line.strip_prefix("- ") {
+                    list.push(item.into());
+                }
+            } else {
+                description.push(line);
+            }
+        }
+        room.description = description.join("\n").trim().into();
+        Some(room)
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "title": self.title,
+            "description": self.description,
+            "exits": self.exits,
+            "things": self.things,
+        })
+    }
+}
+
+/// Parses the output of `inv`, if that's what this is
+pub(crate) fn parse_inventory(text: &str) -> Option<Vec<String>> {
+    let mut lines = text.lines().skip_while(|line| *line != "Your inventory:");
+    lines.next()?;
+    let items = lines
+        .map_while(|line| line.strip_prefix("- "))
+        .map(String::from)
+        .collect();
+    Some(items)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::game::Game;
+
+    #[test]
+    fn test_parse_room() {
+        let text = "
+
+Chiseled on the wall of one of the passageways, you see:
+
+    zJfAmIBPFOIy
+
+You take note of this and keep walking.
+
+== Twisty passages ==
+You are in a twisty alike of little passages, all maze.
+
+The east passage appears very dark; you feel likely to be eaten by a Grue.
+
+Things of interest here:
+- can
+
+There is 1 exit:
+- west
+
+What do you do?
+";
+        let room = Room::parse(text).unwrap();
+        assert_eq!(room.title, "Twisty passages");
+        assert_eq!(
+            room.description,
+            "You are in a twisty alike of little passages, all maze.\n\n\
+             The east passage appears very dark; you feel likely to be eaten by a Grue."
+        );
+        assert_eq!(room.things, vec!["can"]);
+        assert_eq!(room.exits, vec!["west"]);
+        assert_eq!(Room::parse("\n\nTaken.\n\nWhat do you do?\n"), None);
+    }
+
+    #[test]
+    fn test_parse_inventory() {
+        let text = "\n\nYour inventory:\n- tablet\n- empty lantern\n\nWhat do you do?\n";
+        assert_eq!(
+            parse_inventory(text),
+            Some(vec!["tablet".into(), "empty lantern".into()])
+        );
+        assert_eq!(
+            parse_inventory("\n\nYour inventory:\n\nWhat do you do?\n"),
+            Some(vec![])
+        );
+        assert_eq!(parse_inventory("\n\nTaken.\n"), None);
+    }
+
+    #[test]
+    fn test_replay_transcript() {
+        let mut game = Game::new();
+        let mut rooms = vec![Room::parse(&game.run().text).unwrap()];
+        for command in include_str!("../replay.txt").lines() {
+            let reply = game.send(command);
+            if command.starts_with("go ") {
+                rooms.push(Room::parse(&reply.text).unwrap());
+            }
+        }
+        assert!(rooms.iter().all(|room| !room.exits.is_empty()));
+        assert_eq!(rooms[0].things, vec!["tablet"]);
+        assert_eq!(rooms[0].exits, vec!["doorway", "south"]);
+        let vault = rooms.last().unwrap();
+        assert_eq!(vault.title, "Vault");
+        assert_eq!(vault.things, vec!["mirror"]);
+        assert_eq!(vault.exits, vec!["leave"]);
+    }
+}
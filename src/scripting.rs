@@ -0,0 +1,308 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use rhai::{Array, Dynamic, Engine, EvalAltResult, FnPtr, Scope, AST};
+
+use crate::{symbols::Symbols, VM};
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// How a script asked for execution to continue once it returns
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Resume {
+    Step,
+    Continue,
+}
+
+/// The effects of running a script, to be applied by the debugger
+#[derive(Default)]
+pub(crate) struct Outcome {
+    pub(crate) resume: Option<Resume>,
+    pub(crate) breakpoints: Vec<u16>,
+    pub(crate) input: String,
+}
+
+/// State shared between the engine's bindings. The VM is moved in for the
+/// duration of each evaluation, since bindings can't borrow it.
+#[derive(Default)]
+struct State {
+    vm: VM,
+    outcome: Outcome,
+    callbacks: HashMap<u16, FnPtr>,
+}
+
+type Shared = Rc<RefCell<State>>;
+
+#[derive(Clone)]
+struct VmHandle(Shared);
+
+#[derive(Clone)]
+struct Registers(Shared);
+
+#[derive(Clone)]
+struct Memory(Shared);
+
+/// A Rhai engine for automating the debugger. Scripts see a `vm` variable with
+/// `pc`, `registers[n]`, `memory[addr]` and `stack` (all writable), and can call:
+///
+/// - `step()` / `cont()` to resume execution once the script returns
+/// - `break_at(addr)` to add a breakpoint
+/// - `on_break(addr, || ...)` to run a closure at a breakpoint, which resumes
+///   execution if it returns true (or calls `step()`/`cont()`)
+/// - `send(text)` to queue a line of game input, which only goes into the
+///   replay file if the shell's `record` is on
+/// - `sym(name)` to look up a symbol's address
+///
+/// Variables and functions persist between evaluations.
+pub(crate) struct Scripting {
+    engine: Engine,
+    scope: Scope<'static>,
+    functions: AST,
+    state: Shared,
+}
+
+impl Scripting {
+    pub(crate) fn new(symbols: Symbols) -> Self {
+        let state = Shared::default();
+        let mut engine = Engine::new();
+        let symbols = Rc::new(symbols);
+        register_vm(&mut engine);
+        let resolve = {
+            let symbols = symbols.clone();
+            move |name: &str| -> ScriptResult<i64> {
+                match symbols.resolve(name) {
+                    Some(addr) => Ok(addr as i64),
+                    None => Err(format!("Unknown symbol: {name}").into()),
+                }
+            }
+        };
+        engine.register_fn("sym", resolve.clone());
+        {
+            let state = state.clone();
+            engine.register_fn("step", move || {
+                state.borrow_mut().outcome.resume = Some(Resume::Step);
+            });
+        }
+        {
+            let state = state.clone();
+            engine.register_fn("cont", move || {
+                state.borrow_mut().outcome.resume = Some(Resume::Continue);
+            });
+        }
+        {
+            let state = state.clone();
+            engine.register_fn("send", move |text: &str| {
+                let outcome = &mut state.borrow_mut().outcome;
+                outcome.input.push_str(text);
+                outcome.input.push('\n');
+            });
+        }
+        {
+            let state = state.clone();
+            engine.register_fn("break_at", move |addr: i64| -> ScriptResult<()> {
+                let addr = check_address(addr)?;
+                state.borrow_mut().outcome.breakpoints.push(addr);
+                Ok(())
+            });
+        }
+        {
+            let state = state.clone();
+            let resolve = resolve.clone();
+            engine.register_fn("break_at", move |name: &str| -> ScriptResult<()> {
+                let addr = resolve(name)? as u16;
+                state.borrow_mut().outcome.breakpoints.push(addr);
+                Ok(())
+            });
+        }
+        {
+            let state = state.clone();
+            engine.register_fn(
+                "on_break",
+                move |addr: i64, callback: FnPtr| -> ScriptResult<()> {
+                    let addr = check_address(addr)?;
+                    let mut state = state.borrow_mut();
+                    state.outcome.breakpoints.push(addr);
+                    state.callbacks.insert(addr, callback);
+                    Ok(())
+                },
+            );
+        }
+        {
+            let state = state.clone();
+            engine.register_fn(
+                "on_break",
+                move |name: &str, callback: FnPtr| -> ScriptResult<()> {
+                    let addr = resolve(name)? as u16;
+                    let mut state = state.borrow_mut();
+                    state.outcome.breakpoints.push(addr);
+                    state.callbacks.insert(addr, callback);
+                    Ok(())
+                },
+            );
+        }
+        let mut scope = Scope::new();
+        scope.push("vm", VmHandle(state.clone()));
+        Self {
+            engine,
+            scope,
+            functions: AST::empty(),
+            state,
+        }
+    }
+
+    pub(crate) fn has_callback(&self, addr: u16) -> bool {
+        self.state.borrow().callbacks.contains_key(&addr)
+    }
+
+    /// Evaluates a script, printing its result (if any) or the error
+    pub(crate) fn eval(&mut self, vm: &mut VM, code: &str) -> Outcome {
+        self.with_vm(vm, |scripting| {
+            let ast = scripting.engine.compile(code)?;
+            scripting.functions += ast.clone_functions_only();
+            let result: Dynamic =
+                (scripting.engine).eval_ast_with_scope(&mut scripting.scope, &ast)?;
+            if !result.is_unit() {
+                println!("{result}");
+            }
+            Ok(())
+        })
+    }
+
+    /// Runs the callback registered for the breakpoint at pc
+    pub(crate) fn on_breakpoint(&mut self, vm: &mut VM) -> Outcome {
+        let Some(callback) = self.state.borrow().callbacks.get(&vm.pc).cloned() else {
+            return Outcome::default();
+        };
+        self.with_vm(vm, |scripting| {
+            let result: Dynamic = callback.call(&scripting.engine, &scripting.functions, ())?;
+            if result.as_bool() == Ok(true) {
+                let mut state = scripting.state.borrow_mut();
+                state.outcome.resume.get_or_insert(Resume::Continue);
+            }
+            Ok(())
+        })
+    }
+
+    fn with_vm(
+        &mut self,
+        vm: &mut VM,
+        f: impl FnOnce(&mut Self) -> Result<(), Box<dyn std::error::Error>>,
+    ) -> Outcome {
+        std::mem::swap(vm, &mut self.state.borrow_mut().vm);
+        if let Err(err) = f(self) {
+            println!("Script error: {err}");
+        }
+        let mut state = self.state.borrow_mut();
+        std::mem::swap(vm, &mut state.vm);
+        std::mem::take(&mut state.outcome)
+    }
+}
+
+fn check_address(addr: i64) -> ScriptResult<u16> {
+    match u16::try_from(addr) {
+        Ok(addr) if addr < 32768 => Ok(addr),
+        _ => Err(format!("Address out of bounds: {addr}").into()),
+    }
+}
+
+fn check_value(value: i64) -> ScriptResult<u16> {
+    match u16::try_from(value) {
+        Ok(value) if value < 32768 => Ok(value),
+        _ => Err(format!("Value must be 0..=32767, got: {value}").into()),
+    }
+}
+
+fn register_vm(engine: &mut Engine) {
+    engine
+        .register_type_with_name::<VmHandle>("VM")
+        .register_get("pc", |vm: &mut VmHandle| vm.0.borrow().vm.pc as i64)
+        .register_set("pc", |vm: &mut VmHandle, pc: i64| -> ScriptResult<()> {
+            vm.0.borrow_mut().vm.pc = check_address(pc)?;
+            Ok(())
+        })
+        .register_get("registers", |vm: &mut VmHandle| Registers(vm.0.clone()))
+        .register_get("memory", |vm: &mut VmHandle| Memory(vm.0.clone()))
+        .register_get("stack", |vm: &mut VmHandle| -> Array {
+            let state = vm.0.borrow();
+            state
+                .vm
+                .stack
+                .iter()
+                .map(|&v| Dynamic::from(v as i64))
+                .collect()
+        })
+        .register_set(
+            "stack",
+            |vm: &mut VmHandle, stack: Array| -> ScriptResult<()> {
+                let stack = stack
+                    .into_iter()
+                    .map(|v| check_value(v.as_int()?))
+                    .collect::<ScriptResult<Vec<_>>>()?;
+                vm.0.borrow_mut().vm.stack = stack;
+                Ok(())
+            },
+        );
+    engine
+        .register_type_with_name::<Registers>("Registers")
+        .register_indexer_get(|regs: &mut Registers, reg: i64| -> ScriptResult<i64> {
+            let state = regs.0.borrow();
+            match state.vm.registers.get(reg as usize) {
+                Some(&value) if reg >= 0 => Ok(value as i64),
+                _ => Err(format!("Reg number must be 0..=7, got: {reg}").into()),
+            }
+        })
+        .register_indexer_set(
+            |regs: &mut Registers, reg: i64, value: i64| -> ScriptResult<()> {
+                let value = check_value(value)?;
+                let mut state = regs.0.borrow_mut();
+                match state.vm.registers.get_mut(reg as usize) {
+                    Some(slot) if reg >= 0 => *slot = value,
+                    _ => return Err(format!("Reg number must be 0..=7, got: {reg}").into()),
+                }
+                Ok(())
+            },
+        );
+    engine
+        .register_type_with_name::<Memory>("Memory")
+        .register_indexer_get(|mem: &mut Memory, addr: i64| -> ScriptResult<i64> {
+            let addr = check_address(addr)?;
+            Ok(mem.0.borrow().vm.memory[addr as usize] as i64)
+        })
+        .register_indexer_set(
+            |mem: &mut Memory, addr: i64, value: i64| -> ScriptResult<()> {
+                let (addr, value) = (check_address(addr)?, check_value(value)?);
+                mem.0.borrow_mut().vm.memory[addr as usize] = value;
+                Ok(())
+            },
+        );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bindings() {
+        let mut scripting = Scripting::new(Symbols::parse("confirm_check = 6027").unwrap());
+        let mut vm = VM::default();
+        vm.memory[10] = 3;
+        let outcome = scripting.eval(
+            &mut vm,
+            "vm.registers[7] = vm.memory[10] + 1;
+             vm.stack = [1, 2];
+             on_break(sym(\"confirm_check\"), || { vm.registers[0] = 6; true });
+             send(\"take can\");
+             step();",
+        );
+        assert_eq!(vm.registers[7], 4);
+        assert_eq!(vm.stack, vec![1, 2]);
+        assert_eq!(outcome.resume, Some(Resume::Step));
+        assert_eq!(outcome.breakpoints, vec![6027]);
+        assert_eq!(outcome.input, "take can\n");
+
+        vm.pc = 6027;
+        assert!(scripting.has_callback(6027));
+        let outcome = scripting.on_breakpoint(&mut vm);
+        assert_eq!(vm.registers[0], 6);
+        assert_eq!(outcome.resume, Some(Resume::Continue));
+    }
+}
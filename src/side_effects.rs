@@ -1,6 +1,7 @@
 use std::{
+    collections::VecDeque,
     fs::File,
-    io::{stdin, Read, Seek, Write},
+    io::{stdin, LineWriter, Read, Seek, Write},
     process::exit,
 };
 
@@ -33,13 +34,24 @@ impl SideEffects for BasicSideEffects {
             Ok(1) => (),
             _ => panic!("Failed to read a character from stdin"),
         }
-        return buf[0] as u16;
+        buf[0] as u16
     }
 }
 
+/// Replays input from a file, then reads from stdin, recording what's typed
+/// to the file so the next session replays it too
 pub(crate) struct FileBackedEffects {
     file_path: String,
     pos: u64,
+    /// Input queued by the debugger, read before the file from the next line
+    /// on, and whether to record each byte to the file as it's read
+    pending: VecDeque<(u8, bool)>,
+    /// Whether the last byte read ended a line
+    line_start: bool,
+    /// Whether the line being read came from the queue
+    queued_line: bool,
+    /// Appends what's typed to the file, a line at a time
+    typed: Option<LineWriter<File>>,
 }
 
 impl FileBackedEffects {
@@ -47,36 +59,76 @@ impl FileBackedEffects {
         Self {
             file_path: path.into(),
             pos: 0,
+            pending: VecDeque::new(),
+            line_start: true,
+            queued_line: false,
+            typed: None,
         }
     }
 
+    /// Whether there's nothing left to read but stdin
     pub(crate) fn exhausted(&self) -> bool {
+        self.pending.is_empty() && !self.replaying()
+    }
+
+    /// Whether there's more of the file to replay
+    fn replaying(&self) -> bool {
         let file_size = match std::fs::metadata(&self.file_path) {
             Ok(md) => md.len(),
             _ => 0,
         };
-        self.pos >= file_size
+        self.pos < file_size
     }
-}
 
-impl SideEffects for FileBackedEffects {
-    fn print(&mut self, value: u16) {
-        let Some(c) = char::from_u32(value as u32) else {
-            panic!("Value is not an ascii character: {value}");
-        };
-        print!("{c}");
+    /// Queues input to read next, recording it in the file only if asked to
+    pub(crate) fn queue(&mut self, text: &str, record: bool) {
+        self.pending.extend(text.bytes().map(|byte| (byte, record)));
     }
 
-    fn read(&mut self) -> u16 {
+    /// Writes a queued line, starting with `first` and running on through
+    /// the queue, into the file where the replay has reached, so it's
+    /// replayed in the same place next time
+    fn record_line(&mut self, first: u8) {
+        let mut line = vec![first];
+        for &(byte, record) in &self.pending {
+            if !record || line.last() == Some(&b'\n') {
+                break;
+            }
+            line.push(byte);
+        }
+        if let Some(typed) = &mut self.typed {
+            typed.flush().expect("Failed to write to file");
+        }
+        let mut contents = std::fs::read(&self.file_path).unwrap_or_default();
+        let pos = (self.pos as usize).min(contents.len());
+        contents.splice(pos..pos, line);
+        if std::fs::write(&self.file_path, contents).is_err() {
+            panic!("Failed to open file for writing: {}", self.file_path);
+        }
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        if !self.pending.is_empty() && (self.line_start || self.queued_line) {
+            let (byte, record) = self.pending.pop_front().unwrap();
+            if record {
+                if self.line_start {
+                    self.record_line(byte);
+                }
+                self.pos += 1;
+            }
+            self.queued_line = true;
+            return byte;
+        }
+        self.queued_line = false;
         let mut buf = [0; 1];
-        if !self.exhausted() {
+        if self.replaying() {
             // Read from the file
             let Ok(mut file) = File::open(&self.file_path) else {
                 panic!("Failed to open file for reading: {}", self.file_path);
             };
             file.seek(std::io::SeekFrom::Start(self.pos))
                 .expect("Failed to seek to pos");
-            file.read(&mut buf).expect("Failed to read from file");
+            file.read_exact(&mut buf).expect("Failed to read from file");
         } else {
             // Read from stdin
             let reader = stdin();
@@ -85,13 +137,41 @@ impl SideEffects for FileBackedEffects {
                 Ok(1) => (),
                 _ => panic!("Failed to read a character from stdin"),
             }
-            let Ok(mut file) = File::options().append(true).open(&self.file_path) else {
+            self.append(buf[0]);
+        }
+        self.pos += 1;
+        buf[0]
+    }
+
+    /// Appends a byte typed at stdin to the file
+    fn append(&mut self, byte: u8) {
+        if self.typed.is_none() {
+            let file = File::options()
+                .create(true)
+                .append(true)
+                .open(&self.file_path);
+            let Ok(file) = file else {
                 panic!("Failed to open file for writing: {}", self.file_path);
             };
-            file.write(&buf).expect("Failed to write to file");
+            self.typed = Some(LineWriter::new(file));
         }
-        self.pos += 1;
-        return buf[0] as u16;
+        let typed = self.typed.as_mut().unwrap();
+        typed.write_all(&[byte]).expect("Failed to write to file");
+    }
+}
+
+impl SideEffects for FileBackedEffects {
+    fn print(&mut self, value: u16) {
+        let Some(c) = char::from_u32(value as u32) else {
+            panic!("Value is not an ascii character: {value}");
+        };
+        print!("{c}");
+    }
+
+    fn read(&mut self) -> u16 {
+        let byte = self.read_byte();
+        self.line_start = byte == b'\n';
+        byte as u16
     }
 
     fn halt(&mut self) {
@@ -99,6 +179,42 @@ impl SideEffects for FileBackedEffects {
     }
 }
 
+/// Collects output and serves input from a queue, for driving the game programmatically.
+/// Callers must check the VM isn't about to read before the queue runs dry.
+#[derive(Clone, Default)]
+pub(crate) struct BufferedSideEffects {
+    pub(crate) halted: bool,
+    pub(crate) output: String,
+    pub(crate) input: VecDeque<u8>,
+}
+
+impl BufferedSideEffects {
+    pub(crate) fn send(&mut self, text: &str) {
+        self.input.extend(text.bytes());
+    }
+}
+
+impl SideEffects for BufferedSideEffects {
+    fn print(&mut self, value: u16) {
+        let Some(c) = char::from_u32(value as u32) else {
+            panic!("Value is not an ascii character: {value}");
+        };
+        self.output.push(c);
+    }
+
+    fn read(&mut self) -> u16 {
+        match self.input.pop_front() {
+            Some(c) => c as u16,
+            None => panic!("No input available"),
+        }
+    }
+
+    fn halt(&mut self) {
+        self.halted = true;
+    }
+}
+
+#[cfg(test)]
 #[derive(Default)]
 pub(crate) struct MockSideEffects {
     pub(crate) halted: bool,
@@ -106,6 +222,7 @@ pub(crate) struct MockSideEffects {
     pub(crate) input: Vec<char>,
 }
 
+#[cfg(test)]
 impl SideEffects for MockSideEffects {
     fn print(&mut self, value: u16) {
         let Some(c) = char::from_u32(value as u32) else {
@@ -122,3 +239,21 @@ impl SideEffects for MockSideEffects {
         self.halted = true;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_buffered() {
+        let mut side_effects = BufferedSideEffects::default();
+        side_effects.send("go\n");
+        let read = [(); 3].map(|_| side_effects.read());
+        assert_eq!(read, [b'g' as u16, b'o' as u16, b'\n' as u16]);
+        assert!(side_effects.input.is_empty());
+        "Hi!".chars().for_each(|c| side_effects.print(c as u16));
+        side_effects.halt();
+        assert_eq!(side_effects.output, "Hi!");
+        assert!(side_effects.halted);
+    }
+}
@@ -0,0 +1,180 @@
+use crate::{
+    coins::CoinPuzzle,
+    explore::WorldMap,
+    game::{Game, Reply},
+    orb_maze::Maze,
+    planner::Plan,
+};
+
+/// Plays the game from the start to the final code, recording every command
+/// so the playthrough can be replayed. Each stage finds what it needs by
+/// trying things on snapshots rather than assuming where things are:
+///
+/// 1. Collect items, lighting the lantern on the way, up to the coins
+/// 2. Find the monument, and use the coins in the order its equation needs
+/// 3. Collect the teleporter and use it, with the debugger's patches standing
+///    in for the confirmation check unless the game swaps in the lifted one, then
+///    collect what's on the island
+/// 4. Read the vault lock's grid from its rooms, and carry the orb through it
+/// 5. Take the mirror from the vault and use it
+///
+/// Nothing depends on where rooms, items or puzzle values are kept, so this
+/// works on variants of the binary that differ in those (coin values, the
+/// vault's grid, room layout). Rooms are told apart by a room pointer found by
+/// probing the game, or by their text if there isn't one. The one exception is
+/// the teleporter: the debugger's patches are at fixed addresses in our
+/// binary's confirmation code, so on a variant whose code is laid out
+/// differently, swap in the lifted confirmation with that binary's magic number.
+/// Snapshots keep the swap, so planning sees the teleporter as it really works.
+pub(crate) struct Solver {
+    game: Game,
+    pub(crate) commands: Vec<String>,
+    /// Everything the game printed along the way
+    pub(crate) transcript: String,
+}
+
+impl Solver {
+    pub(crate) fn solve(game: Game) -> Self {
+        let mut solver = Self::to_vault_lock(game);
+        solver.cross_vault_lock();
+        solver.collect_items(&["orb"]);
+        solver.play("use mirror").expect("Congratulations");
+        solver
+    }
+
+    /// Plays up to the vault antechamber, leaving the orb on its pedestal
+    pub(crate) fn to_vault_lock(game: Game) -> Self {
+        let mut solver = Self {
+            game,
+            commands: Vec::new(),
+            transcript: String::new(),
+        };
+        let reply = solver.game.run();
+        solver.transcript += &reply.text;
+        solver.collect_items(&[]);
+        solver.place_coins();
+        solver.collect_items(&["orb"]);
+        let map = WorldMap::explore(&solver.game, solver.game.look());
+        let Some(antechamber) =
+            (map.rooms.iter()).find(|room| room.room.things.contains(&"orb".into()))
+        else {
+            solver.check_teleporter();
+            panic!("Failed to find the orb");
+        };
+        for command in antechamber.path.clone() {
+            solver.play(&command);
+        }
+        solver
+    }
+
+    /// Explains getting stuck by the teleporter failing its confirmation,
+    /// as it does with the wrong value in the eighth register
+    fn check_teleporter(&self) {
+        let reply = self.game.clone().send("use teleporter");
+        if reply.contains("Miscalibration detected") {
+            panic!("Failed the teleporter's confirmation with this eighth register:\n{reply}");
+        }
+    }
+
+    pub(crate) fn game(&self) -> &Game {
+        &self.game
+    }
+
+    fn play(&mut self, command: &str) -> Reply {
+        if self.game.halted() {
+            panic!("The game ended before {command:?}");
+        }
+        let reply = self.game.send(command);
+        self.commands.push(command.into());
+        self.transcript += &format!("> {command}\n{reply}");
+        reply
+    }
+
+    fn collect_items(&mut self, ignored: &[&str]) {
+        let plan = Plan::collect_items(&self.game, ignored);
+        for command in &plan.commands {
+            self.play(command);
+        }
+    }
+
+    /// Walks to the monument and uses the coins in the order that balances its equation
+    fn place_coins(&mut self) {
+        let map = WorldMap::explore(&self.game, self.game.look());
+        let Some(monument) = CoinPuzzle::find_monument(&map) else {
+            panic!("Failed to find where the coins go");
+        };
+        for command in monument.path.clone() {
+            self.play(&command);
+        }
+        let Some(puzzle) = CoinPuzzle::read(&self.game) else {
+            panic!("Failed to read the coins");
+        };
+        let Some(order) = puzzle.solve() else {
+            panic!("Failed to find an order for the coins");
+        };
+        for coin in order {
+            self.play(&format!("use {coin}"));
+        }
+    }
+
+    /// Reads the vault lock from the antechamber, then carries the orb to the vault door
+    fn cross_vault_lock(&mut self) {
+        let Some(maze) = Maze::read(&self.game) else {
+            panic!("Failed to read the vault lock");
+        };
+        self.play("take orb");
+        let Some(moves) = maze.commands() else {
+            panic!("Failed to solve the vault lock");
+        };
+        let (last, moves) = moves.split_last().expect("Failed to solve the vault lock");
+        for command in moves {
+            self.play(command);
+        }
+        self.play(last)
+            .expect("You hear a click from the vault door");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_solve() {
+        let solver = Solver::solve(Game::new());
+        assert!(solver.transcript.contains("Congratulations"));
+        // The solution replays from scratch
+        let mut game = Game::new();
+        game.run();
+        let (last, commands) = solver.commands.split_last().unwrap();
+        for command in commands {
+            game.send(command);
+        }
+        game.send(last).expect("Congratulations");
+
+        // Planned from scratch with the lifted confirmation swapped in for the
+        // patches, as a variant of the binary needs, it plays the same
+        let mut game = Game::new();
+        game.swap_confirmation(25734);
+        let swapped = Solver::solve(game);
+        assert_eq!(swapped.commands, solver.commands);
+        assert_eq!(swapped.transcript, solver.transcript);
+
+        // A wrong magic number fails the confirmation, where the patches let it through
+        // Copies of the game, which planning runs on, fail it too
+        let mut game = Game::new();
+        game.swap_confirmation(1);
+        game.run();
+        let mut commands = solver.commands.iter();
+        let reply = loop {
+            let command = commands.next().unwrap();
+            let copied = game.clone().send(command);
+            let reply = game.send(command);
+            assert_eq!(copied, reply);
+            if reply.contains("Miscalibration detected") {
+                break reply;
+            }
+        };
+        assert!(!reply.contains("beach"));
+    }
+}
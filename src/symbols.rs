@@ -0,0 +1,219 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+};
+
+use itertools::Itertools;
+
+/// What a symbol refers to, which affects how it is displayed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SymbolKind {
+    /// The entry point of a routine
+    Function,
+    /// One or more plain memory cells
+    Word,
+    /// A length-prefixed string
+    Str,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Symbol {
+    pub(crate) name: String,
+    pub(crate) address: u16,
+    pub(crate) kind: SymbolKind,
+    pub(crate) length: u16,
+}
+
+/// Names for addresses in the challenge binary, loaded from a file of lines like:
+///
+/// ```text
+/// # comment
+/// print_string = 1518
+/// confirm_check = 6027 fn
+/// current_room = 2732 u16
+/// inventory = 2670 u16 4
+/// ```
+#[derive(Clone, Default)]
+pub(crate) struct Symbols {
+    by_name: HashMap<String, Symbol>,
+    by_address: BTreeMap<u16, Symbol>,
+}
+
+impl Symbols {
+    pub(crate) fn load(path: &str) -> Self {
+        let Ok(text) = std::fs::read_to_string(path) else {
+            panic!("Failed to open file for reading: {path}");
+        };
+        match Self::parse(&text) {
+            Ok(symbols) => symbols,
+            Err(err) => panic!("Invalid symbol file {path}: {err}"),
+        }
+    }
+
+    pub(crate) fn parse(text: &str) -> Result<Self, String> {
+        let mut symbols = Self::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let symbol =
+                Self::parse_line(line).map_err(|err| format!("line {}: {err}", number + 1))?;
+            if symbols.by_name.contains_key(&symbol.name) {
+                return Err(format!(
+                    "line {}: duplicate symbol {}",
+                    number + 1,
+                    symbol.name
+                ));
+            }
+            symbols.by_address.insert(symbol.address, symbol.clone());
+            symbols.by_name.insert(symbol.name.clone(), symbol);
+        }
+        Ok(symbols)
+    }
+
+    fn parse_line(line: &str) -> Result<Symbol, String> {
+        let Some((name, rest)) = line.split_once('=') else {
+            return Err("expected format: name = address [type [length]]".into());
+        };
+        let name = name.trim();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("invalid symbol name: {name}"));
+        }
+        if name.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(format!("symbol name can't start with a digit: {name}"));
+        }
+        let mut fields = rest.split_whitespace();
+        let Some(address) = fields.next() else {
+            return Err(format!("missing address for {name}"));
+        };
+        let Ok(address) = address.parse::<u16>() else {
+            return Err(format!("expected integer, found: {address}"));
+        };
+        if address >= 32768 {
+            return Err(format!("address out of bounds: {address}"));
+        }
+        let kind = match fields.next() {
+            None | Some("fn") => SymbolKind::Function,
+            Some("u16") => SymbolKind::Word,
+            Some("str") => SymbolKind::Str,
+            Some(kind) => return Err(format!("unknown type (expected fn, u16 or str): {kind}")),
+        };
+        let length = match fields.next() {
+            None => 1,
+            Some(length) => match length.parse() {
+                Ok(length) if length > 0 => length,
+                _ => return Err(format!("expected positive integer, found: {length}")),
+            },
+        };
+        if let Some(extra) = fields.next() {
+            return Err(format!("unexpected trailing text: {extra}"));
+        }
+        Ok(Symbol {
+            name: name.into(),
+            address,
+            kind,
+            length,
+        })
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&Symbol> {
+        self.by_name.get(name)
+    }
+
+    /// The symbol defined exactly at the given address
+    pub(crate) fn at(&self, address: u16) -> Option<&Symbol> {
+        self.by_address.get(&address)
+    }
+
+    /// Parses an integer, a symbol name, or a symbol name with an offset (e.g. `inventory+2`)
+    pub(crate) fn resolve(&self, text: &str) -> Option<u16> {
+        if let Ok(value) = text.parse() {
+            return Some(value);
+        }
+        let (name, offset) = match text.split_once('+') {
+            Some((name, offset)) => (name, offset.parse::<u16>().ok()?),
+            None => (text, 0),
+        };
+        self.get(name)?.address.checked_add(offset)
+    }
+
+    /// Describes an address relative to the closest symbol, e.g. `confirm_check+3`.
+    /// Addresses past the end of a data symbol, or before any function, have no label.
+    pub(crate) fn label(&self, address: u16) -> Option<String> {
+        let (_, symbol) = self.by_address.range(..=address).next_back()?;
+        let offset = address - symbol.address;
+        if symbol.kind != SymbolKind::Function && offset >= symbol.length {
+            return None;
+        }
+        if offset == 0 {
+            Some(symbol.name.clone())
+        } else {
+            Some(format!("{}+{offset}", symbol.name))
+        }
+    }
+
+    /// Formats an address along with its label, if it has one
+    pub(crate) fn describe(&self, address: u16) -> String {
+        match self.label(address) {
+            Some(label) => format!("{address} <{label}>"),
+            None => address.to_string(),
+        }
+    }
+
+    /// Comment listing the symbols referred to by the given addresses, if any
+    pub(crate) fn annotate(&self, addresses: impl IntoIterator<Item = u16>) -> String {
+        let names = addresses
+            .into_iter()
+            .filter_map(|address| self.at(address))
+            .map(|symbol| &symbol.name)
+            .join(", ");
+        if names.is_empty() {
+            names
+        } else {
+            format!("  ; {names}")
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.by_address.values()
+    }
+}
+
+impl Display for SymbolKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                SymbolKind::Function => "fn",
+                SymbolKind::Word => "u16",
+                SymbolKind::Str => "str",
+            }
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve_and_label() {
+        let symbols = Symbols::parse(
+            "# routines\n\
+             confirm_check = 6027\n\
+             inventory = 2670 u16 4 # item slots\n",
+        )
+        .unwrap();
+        assert_eq!(symbols.resolve("6027"), Some(6027));
+        assert_eq!(symbols.resolve("confirm_check"), Some(6027));
+        assert_eq!(symbols.resolve("inventory+2"), Some(2672));
+        assert_eq!(symbols.resolve("missing"), None);
+        assert_eq!(symbols.label(6030).as_deref(), Some("confirm_check+3"));
+        assert_eq!(symbols.label(2673).as_deref(), Some("inventory+3"));
+        assert_eq!(symbols.label(2674), None);
+        assert_eq!(symbols.label(100), None);
+        assert!(Symbols::parse("bad = 12 u8").is_err());
+    }
+}
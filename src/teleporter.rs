@@ -1,64 +1,117 @@
 use indicatif::{ProgressBar, ProgressStyle};
-use rayon::{
-    prelude::{IntoParallelIterator, ParallelIterator},
-    ThreadPoolBuilder,
-};
-use std::{collections::HashMap, sync::Mutex};
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use std::{ops::Range, sync::Mutex};
 
+const MODULUS: usize = 32768;
+
+/// The teleporter's confirmation routine, for one value of the eighth register:
+///
+/// ```text
+/// f(0, b) = b + 1
+/// f(a, 0) = f(a - 1, magic)
+/// f(a, b) = f(a - 1, f(a, b - 1))
+/// ```
+///
+/// all modulo 32768. Each row `f(a, _)` only looks things up in the row
+/// before it, so rows are filled in as whole tables, one after another,
+/// rather than by recursing.
 pub(crate) struct Teleporter {
-    memo: HashMap<(u16, u16), u16>,
     magic: u16,
+    row: Vec<u16>,
+    next: Vec<u16>,
 }
 
 impl Teleporter {
-    pub(crate) fn run() {
-        // Magic number is: 25734
-        ThreadPoolBuilder::default()
-            .stack_size(100_000_000)
-            .build_global()
-            .unwrap();
-        println!("Trying all possible values: 0-32767");
+    /// Prints the magic numbers that make `confirm` come out as `target`
+    pub(crate) fn run(
+        (a, b): (u16, u16),
+        target: u16,
+        range: Range<u16>,
+        first_only: bool,
+        confirm: impl Fn(u16) -> u16 + Sync,
+    ) {
+        // For the challenge binary, f(4, 1) = 6 with magic number 25734
+        let range = range.start..range.end.min(MODULUS as u16);
+        println!(
+            "Trying values {}-{} for f({a}, {b}) = {target}",
+            range.start,
+            range.end.saturating_sub(1)
+        );
+        let found = Teleporter::find(target, range, first_only, confirm);
+        if found.is_empty() {
+            println!("No magic number works");
+        }
+        for magic in found {
+            println!("Found magic number: {magic}");
+        }
+    }
 
+    /// Every magic number in the range, lowest first, that makes `confirm`
+    /// come out as `target`, or just the lowest one with `first_only`
+    pub(crate) fn find(
+        target: u16,
+        range: Range<u16>,
+        first_only: bool,
+        confirm: impl Fn(u16) -> u16 + Sync,
+    ) -> Vec<u16> {
         let style = ProgressStyle::default_bar().progress_chars("#>-");
-        let pb = Mutex::new(ProgressBar::new(32768).with_style(style));
-
-        (0..32768)
-            .into_par_iter()
-            .filter_map(|magic| {
-                pb.lock().unwrap().inc(1);
-                if Teleporter::new(magic).check(4, 1) == 6 {
-                    Some(magic)
-                } else {
-                    None
-                }
-            })
-            .for_each(|num| {
-                pb.lock()
-                    .unwrap()
-                    .println(format!("Found magic number: {num}"))
-            });
+        let pb = Mutex::new(ProgressBar::new(range.len() as u64).with_style(style));
+        let works = |&magic: &u16| {
+            pb.lock().unwrap().inc(1);
+            confirm(magic) == target
+        };
+        let found = if first_only {
+            range
+                .into_par_iter()
+                .find_first(works)
+                .into_iter()
+                .collect()
+        } else {
+            range.into_par_iter().filter(works).collect()
+        };
+        pb.lock().unwrap().finish_and_clear();
+        found
     }
 
-    fn new(magic: u16) -> Self {
+    pub(crate) fn new(magic: u16) -> Self {
         Self {
-            memo: HashMap::new(),
             magic,
+            row: Vec::with_capacity(MODULUS),
+            next: vec![0; MODULUS],
         }
     }
 
-    fn check(&mut self, a: u16, b: u16) -> u16 {
-        if let Some(result) = self.memo.get(&(a, b)) {
-            return *result;
+    /// Works out `f(a, b)`, filling only as much of the last row as it needs
+    pub(crate) fn check(&mut self, a: u16, b: u16) -> u16 {
+        self.row.clear();
+        self.row
+            .extend((1..=MODULUS).map(|value| (value % MODULUS) as u16));
+        for n in 1..=a {
+            let len = if n == a { b as usize + 1 } else { MODULUS };
+            self.next[0] = self.row[self.magic as usize];
+            for i in 1..len {
+                self.next[i] = self.row[self.next[i - 1] as usize];
+            }
+            std::mem::swap(&mut self.row, &mut self.next);
         }
-        let result = if a == 0 {
-            (b + 1) % 32768
-        } else if b == 0 {
-            self.check((a + 32767) % 32768, self.magic)
-        } else {
-            let b = self.check(a, (b + 32767) % 32768);
-            self.check((a + 32767) % 32768, b)
-        };
-        self.memo.insert((a, b), result);
-        result
+        self.row[b as usize]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find_magic() {
+        assert_eq!(Teleporter::new(1).check(1, 1), 3);
+        assert_eq!(Teleporter::new(1).check(2, 0), 3);
+        let check = |a, b| move |magic| Teleporter::new(magic).check(a, b);
+        assert_eq!(
+            Teleporter::find(6, 25700..25800, false, check(4, 1)),
+            vec![25734]
+        );
+        assert_eq!(Teleporter::find(6, 0..100, false, check(1, 1)), vec![4]);
+        assert_eq!(Teleporter::find(10, 1..100, true, check(1, 5)), vec![4]);
     }
 }
@@ -0,0 +1,377 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    ops::Range,
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+    instructions::{parse, Instruction, Wmem},
+    VM,
+};
+
+const MAGIC: &[u8; 8] = b"SYNTRACE";
+const VERSION: u16 = 1;
+
+#[derive(clap::ValueEnum, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) enum TraceFormat {
+    /// Compact little-endian records
+    #[default]
+    Binary,
+    /// One JSON object per line
+    Jsonl,
+}
+
+/// What happened when one instruction executed
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct TraceRecord {
+    /// Number of instructions executed before this one
+    pub(crate) step: u64,
+    pub(crate) pc: u16,
+    pub(crate) opcode: u16,
+    /// Operand values, with registers read before execution
+    pub(crate) operands: Vec<u16>,
+    pub(crate) register_writes: Vec<(u8, u16)>,
+    pub(crate) memory_writes: Vec<(u16, u16)>,
+    /// Stack depth after execution
+    pub(crate) stack_depth: u32,
+}
+
+impl TraceRecord {
+    /// Records the state needed before `instruction` executes
+    pub(crate) fn before(step: u64, vm: &VM, instruction: &dyn Instruction) -> Self {
+        Self {
+            step,
+            pc: vm.pc,
+            opcode: instruction.opcode(),
+            operands: (instruction.operands().iter())
+                .map(|operand| operand.value(vm))
+                .collect(),
+            register_writes: Vec::new(),
+            memory_writes: Vec::new(),
+            stack_depth: vm.stack.len() as u32,
+        }
+    }
+
+    /// Fills in the effects of `instruction` once it has executed
+    pub(crate) fn after(&mut self, vm: &VM, instruction: &dyn Instruction) {
+        if let Some(reg) = instruction.register_written() {
+            self.register_writes.push((reg as u8, vm.registers[reg]));
+        }
+        if instruction.opcode() == Wmem::OPCODE {
+            let addr = self.operands[0];
+            self.memory_writes.push((addr, vm.memory[addr as usize]));
+        }
+        self.stack_depth = vm.stack.len() as u32;
+    }
+
+    /// The instruction's name, e.g. `Add`
+    pub(crate) fn op(&self) -> &'static str {
+        // Any operands will do to decode the opcode
+        match parse(&[self.opcode, 0, 0, 0], 0) {
+            Some((instruction, _)) => instruction.name(),
+            None => "?",
+        }
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend(self.step.to_le_bytes());
+        out.extend(self.pc.to_le_bytes());
+        out.extend(self.opcode.to_le_bytes());
+        out.push(self.operands.len() as u8);
+        for operand in &self.operands {
+            out.extend(operand.to_le_bytes());
+        }
+        out.push(self.register_writes.len() as u8);
+        for (reg, value) in &self.register_writes {
+            out.push(*reg);
+            out.extend(value.to_le_bytes());
+        }
+        out.push(self.memory_writes.len() as u8);
+        for (addr, value) in &self.memory_writes {
+            out.extend(addr.to_le_bytes());
+            out.extend(value.to_le_bytes());
+        }
+        out.extend(self.stack_depth.to_le_bytes());
+    }
+
+    fn decode(input: &mut impl Read) -> Option<Self> {
+        fn read<const N: usize>(input: &mut impl Read) -> Option<[u8; N]> {
+            let mut buf = [0; N];
+            input.read_exact(&mut buf).ok()?;
+            Some(buf)
+        }
+        let u16 = |input: &mut _| read(input).map(u16::from_le_bytes);
+        let step = u64::from_le_bytes(read(input)?);
+        let pc = u16(input)?;
+        let opcode = u16(input)?;
+        let [count] = read(input)?;
+        let operands = (0..count).map(|_| u16(input)).collect::<Option<_>>()?;
+        let [count] = read(input)?;
+        let register_writes = (0..count)
+            .map(|_| Some((read::<1>(input)?[0], u16(input)?)))
+            .collect::<Option<_>>()?;
+        let [count] = read(input)?;
+        let memory_writes = (0..count)
+            .map(|_| Some((u16(input)?, u16(input)?)))
+            .collect::<Option<_>>()?;
+        let stack_depth = u32::from_le_bytes(read(input)?);
+        Some(Self {
+            step,
+            pc,
+            opcode,
+            operands,
+            register_writes,
+            memory_writes,
+            stack_depth,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let number = |value: &Value| value.as_u64();
+        let pairs = |value: &Value| -> Option<Vec<(u64, u16)>> {
+            (value.as_array()?.iter())
+                .map(|pair| Some((number(&pair[0])?, number(&pair[1])?.try_into().ok()?)))
+                .collect()
+        };
+        let op = value["op"].as_str()?;
+        let opcode = (0..=21).find(|&opcode| {
+            let record = TraceRecord {
+                opcode,
+                ..Self::default()
+            };
+            record.op() == op
+        })?;
+        Some(Self {
+            step: number(&value["step"])?,
+            pc: number(&value["pc"])?.try_into().ok()?,
+            opcode,
+            operands: (value["operands"].as_array()?.iter())
+                .map(|operand| number(operand)?.try_into().ok())
+                .collect::<Option<_>>()?,
+            register_writes: (pairs(&value["register_writes"])?.into_iter())
+                .map(|(reg, value)| Some((reg.try_into().ok()?, value)))
+                .collect::<Option<_>>()?,
+            memory_writes: (pairs(&value["memory_writes"])?.into_iter())
+                .map(|(addr, value)| Some((addr.try_into().ok()?, value)))
+                .collect::<Option<_>>()?,
+            stack_depth: number(&value["stack_depth"])?.try_into().ok()?,
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "step": self.step,
+            "pc": self.pc,
+            "op": self.op(),
+            "operands": self.operands,
+            "register_writes": self.register_writes,
+            "memory_writes": self.memory_writes,
+            "stack_depth": self.stack_depth,
+        })
+    }
+}
+
+/// Writes a record of every executed instruction (within the address filters) to a file
+pub(crate) struct TraceSink {
+    writer: BufWriter<File>,
+    format: TraceFormat,
+    ranges: Vec<Range<u16>>,
+    buf: Vec<u8>,
+}
+
+impl TraceSink {
+    pub(crate) fn create(path: &str, format: TraceFormat, ranges: Vec<Range<u16>>) -> Self {
+        let Ok(file) = File::create(path) else {
+            panic!("Failed to open file for writing: {path}");
+        };
+        let mut writer = BufWriter::new(file);
+        if format == TraceFormat::Binary {
+            writer
+                .write_all(MAGIC)
+                .and_then(|_| writer.write_all(&VERSION.to_le_bytes()))
+                .expect("Failed to write trace header");
+        }
+        Self {
+            writer,
+            format,
+            ranges,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Whether instructions at this address are recorded
+    pub(crate) fn includes(&self, pc: u16) -> bool {
+        self.ranges.is_empty() || self.ranges.iter().any(|range| range.contains(&pc))
+    }
+
+    pub(crate) fn write(&mut self, record: &TraceRecord) {
+        self.buf.clear();
+        match self.format {
+            TraceFormat::Binary => record.encode(&mut self.buf),
+            TraceFormat::Jsonl => {
+                self.buf.extend(record.to_json().to_string().bytes());
+                self.buf.push(b'\n');
+            }
+        }
+        self.writer
+            .write_all(&self.buf)
+            .expect("Failed to write trace record");
+    }
+
+    pub(crate) fn flush(&mut self) {
+        self.writer.flush().expect("Failed to flush trace");
+    }
+}
+
+/// Reads back the records in a trace file of either format
+pub(crate) struct TraceReader {
+    reader: BufReader<File>,
+    format: TraceFormat,
+    path: String,
+}
+
+impl TraceReader {
+    pub(crate) fn open(path: &str) -> Self {
+        let Ok(file) = File::open(path) else {
+            panic!("Failed to open file for reading: {path}");
+        };
+        let mut reader = BufReader::new(file);
+        let header = reader.fill_buf().expect("Failed to read trace");
+        let format = if header.starts_with(MAGIC) {
+            let mut header = [0; MAGIC.len() + 2];
+            reader
+                .read_exact(&mut header)
+                .expect("Failed to read trace header");
+            let version = u16::from_le_bytes([header[MAGIC.len()], header[MAGIC.len() + 1]]);
+            if version != VERSION {
+                panic!("Unsupported trace version {version} in {path}");
+            }
+            TraceFormat::Binary
+        } else {
+            TraceFormat::Jsonl
+        };
+        Self {
+            reader,
+            format,
+            path: path.into(),
+        }
+    }
+}
+
+impl Iterator for TraceReader {
+    type Item = TraceRecord;
+
+    fn next(&mut self) -> Option<TraceRecord> {
+        match self.format {
+            TraceFormat::Binary => {
+                if self.reader.fill_buf().ok()?.is_empty() {
+                    return None;
+                }
+                let record = TraceRecord::decode(&mut self.reader);
+                Some(record.unwrap_or_else(|| panic!("Truncated trace record in {}", self.path)))
+            }
+            TraceFormat::Jsonl => {
+                let mut line = String::new();
+                if self.reader.read_line(&mut line).ok()? == 0 {
+                    return None;
+                }
+                let record = serde_json::from_str(&line)
+                    .ok()
+                    .and_then(|value| TraceRecord::from_json(&value));
+                Some(
+                    record
+                        .unwrap_or_else(|| panic!("Invalid trace record in {}: {line}", self.path)),
+                )
+            }
+        }
+    }
+}
+
+/// Parses an address range like `5400..6100` (end exclusive)
+pub(crate) fn parse_range(text: &str) -> Result<Range<u16>, String> {
+    let Some((start, end)) = text.split_once("..") else {
+        return Err(format!("expected start..end, found: {text}"));
+    };
+    let parse = |value: &str| {
+        value
+            .parse::<u16>()
+            .map_err(|_| format!("expected integer, found: {value}"))
+    };
+    Ok(parse(start)?..parse(end)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{instructions::Pop, side_effects::MockSideEffects};
+
+    #[test]
+    fn test_round_trip() {
+        let record = TraceRecord {
+            step: 1 << 40,
+            pc: 5489,
+            opcode: Wmem::OPCODE,
+            operands: vec![2732, 7],
+            register_writes: vec![(3, 32767)],
+            memory_writes: vec![(2732, 7)],
+            stack_depth: 12,
+        };
+        let mut buf = Vec::new();
+        record.encode(&mut buf);
+        assert_eq!(
+            TraceRecord::decode(&mut buf.as_slice()),
+            Some(record.clone())
+        );
+        assert_eq!(TraceRecord::from_json(&record.to_json()), Some(record));
+    }
+
+    #[test]
+    fn test_record_and_write() {
+        // wmem 100 reg0, then pop reg3
+        let mut vm = VM::default();
+        vm.memory[..5].copy_from_slice(&[Wmem::OPCODE, 100, 32768, Pop::OPCODE, 32771]);
+        (vm.registers[0], vm.stack) = (7, vec![9, 5]);
+        let mut side_effects = MockSideEffects::default();
+        let mut records = Vec::new();
+        for step in 0..2 {
+            let (instruction, size) = parse(&vm.memory, vm.pc).unwrap();
+            let mut record = TraceRecord::before(step, &vm, instruction.as_ref());
+            vm.pc += size;
+            instruction.execute(&mut vm, &mut side_effects);
+            record.after(&vm, instruction.as_ref());
+            records.push(record);
+        }
+        assert_eq!(
+            (
+                records[0].operands.clone(),
+                records[0].memory_writes.clone()
+            ),
+            (vec![100, 7], vec![(100, 7)])
+        );
+        assert_eq!(records[1].register_writes, vec![(3, 5)]);
+        assert_eq!(records[1].stack_depth, 1);
+
+        let path = std::env::temp_dir().join(format!("trace-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let ranges = vec![parse_range("3..10").unwrap()];
+        let mut sink = TraceSink::create(path, TraceFormat::Jsonl, ranges);
+        assert!(!sink.includes(0) && sink.includes(3));
+        sink.write(&records[1]);
+        sink.flush();
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap(),
+            "{\"memory_writes\":[],\"op\":\"Pop\",\"operands\":[0],\"pc\":3,\"register_writes\":[[3,5]],\"stack_depth\":1,\"step\":1}\n"
+        );
+
+        let mut sink = TraceSink::create(path, TraceFormat::Binary, Vec::new());
+        sink.write(&records[0]);
+        sink.flush();
+        let mut expected = b"SYNTRACE\x01\x00".to_vec();
+        expected.extend([0; 8]);
+        expected.extend([0, 0, 16, 0, 2, 100, 0, 7, 0, 0, 1, 100, 0, 7, 0, 2, 0, 0, 0]);
+        assert_eq!(std::fs::read(path).unwrap(), expected);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use itertools::{EitherOrBoth, Itertools};
+
+use crate::{
+    instructions::parse,
+    symbols::Symbols,
+    trace::{TraceReader, TraceRecord},
+};
+
+/// The machine state implied by the records of one trace so far
+#[derive(Clone, Default)]
+struct ReplayedState {
+    registers: [u16; 8],
+    memory_writes: BTreeMap<u16, u16>,
+}
+
+impl ReplayedState {
+    fn apply(&mut self, record: &TraceRecord) {
+        for &(reg, value) in &record.register_writes {
+            self.registers[reg as usize] = value;
+        }
+        self.memory_writes
+            .extend(record.memory_writes.iter().copied());
+    }
+}
+
+/// Where two traces first differ, and the state of each at that point
+struct Divergence {
+    /// The records leading up to the divergence, common to both traces
+    history: VecDeque<TraceRecord>,
+    a: Option<TraceRecord>,
+    b: Option<TraceRecord>,
+    state_a: ReplayedState,
+    state_b: ReplayedState,
+}
+
+fn first_divergence(
+    a: impl Iterator<Item = TraceRecord>,
+    b: impl Iterator<Item = TraceRecord>,
+    context: usize,
+) -> Option<Divergence> {
+    let mut history = VecDeque::new();
+    let mut state = ReplayedState::default();
+    for pair in a.zip_longest(b) {
+        let (a, b) = match pair {
+            EitherOrBoth::Both(a, b) if a == b => {
+                state.apply(&a);
+                history.push_back(a);
+                if history.len() > context {
+                    history.pop_front();
+                }
+                continue;
+            }
+            EitherOrBoth::Both(a, b) => (Some(a), Some(b)),
+            EitherOrBoth::Left(a) => (Some(a), None),
+            EitherOrBoth::Right(b) => (None, Some(b)),
+        };
+        return Some(Divergence {
+            history,
+            a,
+            b,
+            state_a: state.clone(),
+            state_b: state,
+        });
+    }
+    None
+}
+
+/// Describes a record as `pc <label>: instruction (effects)`, decoding the
+/// instruction from the binary with the trace's memory writes applied
+fn describe(
+    record: &TraceRecord,
+    memory: &[u16],
+    state: &ReplayedState,
+    symbols: &Symbols,
+) -> String {
+    let text = disassemble(record.pc, memory, state)
+        .map(|(text, _)| text)
+        .unwrap_or_else(|| record.op().into());
+    let mut effects = Vec::new();
+    effects
+        .extend((record.register_writes.iter()).map(|(reg, value)| format!("reg{reg} = {value}")));
+    effects.extend(
+        (record.memory_writes.iter())
+            .map(|(addr, value)| format!("[{}] = {value}", symbols.describe(*addr))),
+    );
+    effects.push(format!("stack depth {}", record.stack_depth));
+    format!(
+        "step {}, {}: {text} ({}) operands {:?}",
+        record.step,
+        symbols.describe(record.pc),
+        effects.join(", "),
+        record.operands,
+    )
+}
+
+fn disassemble(addr: u16, memory: &[u16], state: &ReplayedState) -> Option<(String, u16)> {
+    let window = (0..4)
+        .map(|offset| addr as usize + offset)
+        .map(|addr| {
+            (state.memory_writes.get(&(addr as u16)).copied())
+                .or_else(|| memory.get(addr).copied())
+                .unwrap_or(0)
+        })
+        .collect_vec();
+    let (instruction, size) = parse(&window, 0)?;
+    Some((instruction.to_string(), size))
+}
+
+fn print_listing(pc: u16, memory: &[u16], state: &ReplayedState, symbols: &Symbols, count: usize) {
+    let mut addr = pc;
+    for _ in 0..count {
+        let Some((text, size)) = disassemble(addr, memory, state) else {
+            break;
+        };
+        println!("    {}: {text}", symbols.describe(addr));
+        addr += size;
+    }
+}
+
+/// Compares two traces in lockstep and reports where they first diverge
+pub(crate) fn trace_diff(
+    path_a: &str,
+    path_b: &str,
+    memory: &[u16],
+    symbols: &Symbols,
+    context: usize,
+) {
+    let Some(divergence) = first_divergence(
+        TraceReader::open(path_a),
+        TraceReader::open(path_b),
+        context,
+    ) else {
+        println!("Traces are identical");
+        return;
+    };
+    let Divergence {
+        history,
+        a,
+        b,
+        mut state_a,
+        mut state_b,
+    } = divergence;
+    let step = a.as_ref().or(b.as_ref()).map_or(0, |record| record.step);
+    println!("Traces diverge at step {step}");
+    if !history.is_empty() {
+        println!("\nLast {} common steps:", history.len());
+        for record in &history {
+            println!("    {}", describe(record, memory, &state_a, symbols));
+        }
+    }
+    println!();
+    for (name, record, state) in [("a", &a, &state_a), ("b", &b, &state_b)] {
+        match record {
+            Some(record) => println!("{name}: {}", describe(record, memory, state, symbols)),
+            None => println!("{name}: trace ended"),
+        }
+    }
+
+    // Include the diverging step's own writes
+    if let Some(a) = &a {
+        state_a.apply(a);
+    }
+    if let Some(b) = &b {
+        state_b.apply(b);
+    }
+    let registers = (0..8)
+        .filter(|&reg| state_a.registers[reg] != state_b.registers[reg])
+        .collect_vec();
+    if !registers.is_empty() {
+        println!("\nRegisters that differ:");
+        for reg in registers {
+            println!(
+                "    reg{reg}: a = {}, b = {}",
+                state_a.registers[reg], state_b.registers[reg]
+            );
+        }
+    }
+    let addresses = (state_a.memory_writes.keys())
+        .chain(state_b.memory_writes.keys())
+        .unique()
+        .filter(|addr| state_a.memory_writes.get(addr) != state_b.memory_writes.get(addr))
+        .sorted()
+        .collect_vec();
+    if !addresses.is_empty() {
+        println!("\nMemory that differs:");
+        let value = |state: &ReplayedState, addr: &u16| {
+            (state.memory_writes.get(addr).copied())
+                .or_else(|| memory.get(*addr as usize).copied())
+                .map_or("?".into(), |value| value.to_string())
+        };
+        for addr in addresses {
+            println!(
+                "    {}: a = {}, b = {}",
+                symbols.describe(*addr),
+                value(&state_a, addr),
+                value(&state_b, addr)
+            );
+        }
+    }
+    for (name, record, state) in [("a", &a, &state_a), ("b", &b, &state_b)] {
+        if let Some(record) = record {
+            println!("\nCode at {name}'s pc:");
+            print_listing(record.pc, memory, state, symbols, context.max(1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(step: u64, pc: u16, register_writes: Vec<(u8, u16)>) -> TraceRecord {
+        TraceRecord {
+            step,
+            pc,
+            register_writes,
+            ..TraceRecord::default()
+        }
+    }
+
+    #[test]
+    fn test_first_divergence() {
+        let a = vec![
+            record(0, 10, vec![(7, 1)]),
+            record(1, 12, vec![]),
+            record(2, 14, vec![(0, 5)]),
+        ];
+        let mut b = a.clone();
+        b[2] = record(2, 20, vec![(0, 6)]);
+        let divergence = first_divergence(a.clone().into_iter(), b.into_iter(), 1).unwrap();
+        assert_eq!(divergence.history.len(), 1);
+        assert_eq!(divergence.history[0].step, 1);
+        assert_eq!(divergence.state_a.registers[7], 1);
+        assert_eq!(divergence.a.unwrap().pc, 14);
+        assert_eq!(divergence.b.unwrap().pc, 20);
+
+        let divergence = first_divergence(a.clone().into_iter(), a[..2].iter().cloned(), 5);
+        assert!(divergence.unwrap().b.is_none());
+        assert!(first_divergence(a.clone().into_iter(), a.into_iter(), 5).is_none());
+    }
+}